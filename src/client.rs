@@ -4,119 +4,916 @@
 //!
 //!  - Query the `Proxy` for an authz URL.
 //!  - Prompt the `User` to visit the URL (either automtically opening the URL,
-//!    or through copy+paste).
+//!    or through copy+paste). This is customisable through `UrlPresenter`.
 //!  - Wait for the `User` to be redirected back to the locally running
-//!    HTTP server.
+//!    HTTP server, or in headless mode, for the `User` to paste the
+//!    response shown by the `Proxy`.
 
 use actix::Addr;
 use actix_web::{
     http, 
     server::{self, Server},
-    App, HttpResponse, Query, State
+    App, HttpRequest, HttpResponse,
 };
-use lazy_static::lazy_static;
+use failure::Fail;
 use log::*;
 use oauth2::CsrfToken;
 use oauth2::prelude::*;
 use serde::{de::DeserializeOwned, Serialize};
-use serde_derive::Deserialize;
+use std::env;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use url::Url;
 
 use crate::msgs::*;
-use crate::util::*;
+use crate::seal::ClientKey;
+
+mod presenter;
+pub mod store;
+
+pub use self::presenter::{Browser, Callback, QrCodeTerminal, Stderr, UrlPresenter};
+pub use crate::msgs::Delivery;
+
+use self::store::{CredentialStore, StoreKey, StoredCredential};
+
+/// Errors which can occur while authenticating the client.
+#[derive(Debug, Fail)]
+pub enum Error {
+    /// The supplied proxy URL could not be parsed.
+    #[fail(display = "invalid proxy URL: {}", _0)]
+    InvalidProxyUrl(#[cause] url::ParseError),
+
+    /// The proxy could not be reached, or the connection failed.
+    #[fail(display = "could not reach the proxy: {}", _0)]
+    ProxyUnreachable(#[cause] reqwest::Error),
+
+    /// The proxy responded with a non-success status code.
+    #[fail(display = "proxy returned status {}", _0)]
+    ProxyStatus(reqwest::StatusCode),
+
+    /// The CSRF token returned did not match the one we sent.
+    #[fail(display = "CSRF token mismatch, refusing the response")]
+    CsrfMismatch,
+
+    /// The `FinResponse` could not be parsed.
+    #[fail(display = "malformed response from proxy: {}", _0)]
+    MalformedResponse(String),
+
+    /// The local HTTP listener could not be started.
+    #[fail(display = "could not bind local listener: {}", _0)]
+    Bind(#[cause] io::Error),
+
+    /// The user abandoned the authentication process.
+    #[fail(display = "authentication was cancelled")]
+    Cancelled,
+
+    /// The deadline set in `AuthOptions` passed before completion.
+    #[fail(display = "timed out waiting for authentication")]
+    Timeout,
+
+    /// The `User` declined the authorization request.
+    #[fail(display = "authorization was denied")]
+    AccessDenied,
+
+    /// The proxy does not allow one of the scopes asked for.
+    #[fail(display = "the proxy rejected the requested scopes: {}", _0)]
+    ScopeRejected(String),
+
+    /// The proxy no longer accepts the refresh handle,
+    /// e.g. because it was already used or the proxy restarted.
+    #[fail(display = "the proxy rejected the refresh handle")]
+    RefreshRejected,
+
+    /// The ephemeral key the response is sealed to could not be generated.
+    #[fail(display = "could not generate a key: {}", _0)]
+    KeyGeneration(failure::Error),
+
+    /// The credential store could not be read or written.
+    #[fail(display = "credential store error: {}", _0)]
+    Store(failure::Error),
+}
+
+/// Options controlling how long the client waits for the `User`
+/// to complete the authentication.
+#[derive(Clone, Debug, Default)]
+pub struct AuthOptions {
+    /// Overall deadline for the authentication. `None` waits forever.
+    pub timeout: Option<Duration>,
+
+    /// Handle which can be used to abort a pending authentication.
+    pub cancel: Option<CancelHandle>,
+}
+
+/// Handle used to cancel a running authentication, e.g. from
+/// another thread or a signal handler.
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation. The pending `authenticate` call will
+    /// shut down the local listener and return `Error::Cancelled`.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// A response from the proxy, along with what is needed to renew it.
+#[derive(Clone, Debug)]
+pub struct Session<R> {
+    /// The response `R` produced by the proxy's session handler.
+    pub response: R,
+
+    /// Handle for `Client::refresh`, if the provider issued a refresh token.
+    /// Each handle can only be used once.
+    pub refresh_handle: Option<String>,
+
+    /// Lifetime of the access token behind the response, if known.
+    pub expires_in: Option<Duration>,
+
+    /// Scopes granted to the access token behind the response,
+    /// if the proxy reported them.
+    pub scopes: Option<Vec<String>>,
+}
+
+/// How often (in ms) the waiting thread checks for timeout or cancellation.
+const POLL_INTERVAL_MS: u64 = 100;
 
 /// Run the authn process for proxy running at `proxy_url`.
-pub fn authenticate<R>(proxy_url: &str) -> String
-    where R: 'static + DeserializeOwned + Serialize
+///
+/// Returns the response `R` produced by the proxy's session handler.
+/// This is shorthand for `Client::new(proxy_url)?.authenticate()`.
+pub fn authenticate<R>(proxy_url: &str) -> Result<R, Error>
+    where R: 'static + DeserializeOwned + Serialize + Send
 {
-    let sys = actix::System::new("oauth_cli");  // <- create Actix system
-    let token = CsrfToken::new_random();
-    let (tx, rx) = mpsc::sync_channel(1);
-    let tx = Arc::new(tx);
-    let (port, server) = run_oauth_listener::<R>(Arc::new(token.clone()), tx);
-    let params = GenParams {
-        client_port: port,
-        csrf_token: token,
-    };
-    let url = get_authorization_url(&params, Url::parse(proxy_url).unwrap());
-    // info!("Recovered URL: {}", url);
-    println!("Attempting to open URL in browser");
-    let failed = match open::that(url.to_string()) {
-            Ok(s) if s.success() => false,
-            Ok(_) => { println!("Failed to find browser to open URL."); true },
-            Err(_) => { println!("Couldn't find native 'open` command"); true },
-    };
-    if failed {
-        println!(
-            "Open this URL in your browser:\n{}\n",
-            url.to_string()
-        );
-    }
-
-    let current_system = actix::System::current();
-    let arbiter = current_system.arbiter().clone();
-    let arc_secret = Arc::new(Mutex::new(String::new()));
-    let arc_secret2 = arc_secret.clone();
-    thread::spawn(move || {
-        println!("Waiting to receive secret...");
-        let (secret, sys) = rx.recv().unwrap();
-        actix::System::set_current(sys.clone());
-        info!("New secret received: {}", secret);
-        *arc_secret2.lock().unwrap() = secret;
-        server.do_send(actix_web::server::StopServer { graceful: false });
-        sys.stop();
-    });
-    sys.run();  // <- Run actix system, this method starts all async processes
-
-    let secret = arc_secret.lock().unwrap();
-    // arc_secret.lock().unwrap().clone().to_string()
-    secret.clone()
-}
-
-type ChannelMsg = (String, actix::System);
+    authenticate_with_options(proxy_url, AuthOptions::default())
+}
 
-#[derive(Clone, Debug)]
-struct AppState {
-    // server_url: String,
-    nonce: Arc<CsrfToken>,
-    tx: Arc<mpsc::SyncSender<ChannelMsg>>,
+/// Run the authn process for proxy running at `proxy_url`,
+/// giving up when the timeout or cancellation in `options` fires.
+pub fn authenticate_with_options<R>(proxy_url: &str, options: AuthOptions) -> Result<R, Error>
+    where R: 'static + DeserializeOwned + Serialize + Send
+{
+    Client::new(proxy_url)?.options(options).authenticate()
+}
+
+/// Return the response stored for `proxy_url` and `profile` in `store`,
+/// if present and not expired. Otherwise run the authn process,
+/// and store the result.
+pub fn authenticate_cached<R>(proxy_url: &str, store: &dyn CredentialStore, profile: &str) -> Result<R, Error>
+    where R: 'static + DeserializeOwned + Serialize + Send
+{
+    Client::new(proxy_url)?.authenticate_cached(store, profile)
 }
 
-fn run_oauth_listener<R>(nonce: Arc<CsrfToken>, tx: Arc<mpsc::SyncSender<ChannelMsg>>) -> (u16, Addr<Server>)
-    where R: 'static + DeserializeOwned + Serialize
+/// Run the device authorization grant (RFC 8628) with the proxy
+/// running at `proxy_url`, showing the `User` a code to enter in
+/// their browser. No local listener is needed.
+pub fn authenticate_device<R>(proxy_url: &str) -> Result<R, Error>
+    where R: 'static + DeserializeOwned + Serialize + Send
 {
-    let state = AppState { nonce, tx };
+    Client::new(proxy_url)?.authenticate_device()
+}
+
+/// Renew a session with the proxy running at `proxy_url`, using the
+/// `refresh_handle` from an earlier `Session`. The `User` is not involved.
+pub fn refresh<R>(proxy_url: &str, refresh_handle: &str) -> Result<Session<R>, Error>
+    where R: 'static + DeserializeOwned + Serialize + Send
+{
+    Client::new(proxy_url)?.refresh(refresh_handle)
+}
+
+/// Configurable client for authenticating against a proxy.
+///
+/// ```rust,no_run
+/// # use olaf2::client::Client;
+/// # use std::time::Duration;
+/// let secret: String = Client::new("http://127.0.0.1:8081/")?
+///     .timeout(Duration::from_secs(300))
+///     .authenticate()?;
+/// # Ok::<(), olaf2::client::Error>(())
+/// ```
+pub struct Client {
+    proxy_url: Url,
+    bind_address: IpAddr,
+    port_range: Option<(u16, u16)>,
+    start_path: String,
+    presenter: Box<dyn UrlPresenter>,
+    options: AuthOptions,
+    success_page: Option<String>,
+    http_client: reqwest::Client,
+    delivery: Option<Delivery>,
+    device_flow: bool,
+    cache_ttl: Option<Duration>,
+    provider: Option<String>,
+    scopes: Option<Vec<String>>,
+}
+
+impl Client {
+    /// Create a client for the proxy running at `proxy_url`.
+    pub fn new(proxy_url: &str) -> Result<Self, Error> {
+        Ok(Client {
+            proxy_url: Url::parse(proxy_url).map_err(Error::InvalidProxyUrl)?,
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port_range: None,
+            start_path: "oauth-cli/start".to_string(),
+            presenter: Box::new(Browser),
+            options: AuthOptions::default(),
+            success_page: None,
+            http_client: reqwest::Client::new(),
+            delivery: None,
+            device_flow: false,
+            cache_ttl: None,
+            provider: None,
+            scopes: None,
+        })
+    }
+
+    /// Address for the local listener to bind to (default `127.0.0.1`).
+    pub fn bind_address(mut self, addr: IpAddr) -> Self {
+        self.bind_address = addr;
+        self
+    }
+
+    /// Restrict the local listener to a port in `low..=high`,
+    /// instead of a random port chosen by the OS.
+    pub fn port_range(mut self, low: u16, high: u16) -> Self {
+        self.port_range = Some((low, high));
+        self
+    }
+
+    /// Path of the start endpoint, relative to the proxy URL
+    /// (default `oauth-cli/start`).
+    pub fn start_path(mut self, path: &str) -> Self {
+        self.start_path = path.trim_start_matches('/').to_string();
+        self
+    }
+
+    /// Log in with the provider configured under `name` at the
+    /// proxy, rather than its default provider.
+    ///
+    /// This also sets the start path to `oauth-cli/{name}/start`.
+    pub fn provider(mut self, name: &str) -> Self {
+        self.start_path = format!("oauth-cli/{}/start", name);
+        self.provider = Some(name.to_string());
+        self
+    }
+
+    /// Ask for `scopes` in addition to those configured at the proxy,
+    /// which must allow them (see `proxy::Config::allowed_scopes`).
+    ///
+    /// A response stored by `authenticate_cached` without all of
+    /// `scopes` is not used; the `User` is asked to authorize again,
+    /// upgrading the stored response.
+    pub fn scopes(mut self, scopes: &[&str]) -> Self {
+        self.scopes = Some(scopes.iter().map(|s| s.to_string()).collect());
+        self
+    }
+
+    /// How to show the authorization URL to the `User`.
+    /// By default, the URL is opened in the browser.
+    pub fn presenter<P>(mut self, presenter: P) -> Self
+        where P: 'static + UrlPresenter
+    {
+        self.presenter = Box::new(presenter);
+        self
+    }
+
+    /// Show the authorization URL by calling `f`.
+    pub fn present_with<F>(self, f: F) -> Self
+        where F: 'static + Fn(&Url) + Send + Sync
+    {
+        self.presenter(Callback(f))
+    }
+
+    /// Give up after `timeout` has elapsed.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    /// Abort the authentication when `cancel` is triggered.
+    pub fn cancel_handle(mut self, cancel: CancelHandle) -> Self {
+        self.options.cancel = Some(cancel);
+        self
+    }
+
+    /// Replace the timeout and cancellation settings.
+    pub fn options(mut self, options: AuthOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// HTML page served by the local listener once the response has
+    /// been received, instead of redirecting to the proxy's welcome page.
+    pub fn success_page(mut self, html: String) -> Self {
+        self.success_page = Some(html);
+        self
+    }
+
+    /// HTTP client used to talk to the proxy, e.g. for custom
+    /// timeouts, proxies or TLS settings.
+    pub fn http_client(mut self, client: reqwest::Client) -> Self {
+        self.http_client = client;
+        self
+    }
 
-    let server = server::new(move || {
-        App::with_state(state.clone()).resource("/", |r| r.with(handle_response::<R>))
+    /// Force headless mode on or off.
+    ///
+    /// In headless mode no local listener is started. Instead, the proxy
+    /// shows a code in the browser, which the `User` pastes into stdin.
+    /// By default, headless mode is used when running over SSH.
+    pub fn headless(self, headless: bool) -> Self {
+        self.delivery(if headless { Delivery::Headless } else { Delivery::Listener })
+    }
+
+    /// Choose how the response is delivered back to the client.
+    ///
+    /// With `Delivery::Poll`, no local listener is started and the
+    /// client long-polls the proxy for the response instead. The
+    /// HTTP client timeout must exceed the proxy's long-poll interval
+    /// (25 seconds).
+    pub fn delivery(mut self, delivery: Delivery) -> Self {
+        self.delivery = Some(delivery);
+        self
+    }
+
+    /// Use the device authorization grant for `authenticate` (and
+    /// the cached variants), instead of redirecting the browser.
+    pub fn device_flow(mut self, device_flow: bool) -> Self {
+        self.device_flow = device_flow;
+        self
+    }
+
+    /// How long responses saved by `authenticate_cached` remain valid.
+    /// By default they do not expire, unless the proxy reports when
+    /// the underlying access token expires.
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Key for this client's proxy and `profile` in a `CredentialStore`.
+    ///
+    /// Responses from a named `provider` are kept apart from those of
+    /// the default provider, under `{provider}/{profile}`.
+    pub fn store_key(&self, profile: &str) -> StoreKey {
+        match self.provider {
+            Some(ref provider) => StoreKey::new(self.proxy_url.as_str(), &format!("{}/{}", provider, profile)),
+            None => StoreKey::new(self.proxy_url.as_str(), profile),
+        }
+    }
+
+    /// Return the response stored under `profile`, if present and not
+    /// expired. Otherwise try to refresh it, and failing that run the
+    /// authn process. New responses are stored.
+    pub fn authenticate_cached<R>(&self, store: &dyn CredentialStore, profile: &str) -> Result<R, Error>
+        where R: 'static + DeserializeOwned + Serialize + Send
+    {
+        match self.refresh_cached(store, profile)? {
+            Some(response) => Ok(response),
+            None => self.authenticate_and_store(store, profile),
+        }
+    }
+
+    /// Return the response stored under `profile`, refreshing (and
+    /// storing) it if it has expired. Returns `None` if there is no
+    /// usable response, and the `User` needs to log in again.
+    pub fn refresh_cached<R>(&self, store: &dyn CredentialStore, profile: &str) -> Result<Option<R>, Error>
+        where R: 'static + DeserializeOwned + Serialize + Send
+    {
+        let key = self.store_key(profile);
+        if let Some(stored) = store.load(&key).map_err(Error::Store)? {
+            if !self.has_scopes(stored.scopes.as_ref()) {
+                info!("Stored response lacks requested scopes; authorizing again");
+                return Ok(None);
+            }
+            if !stored.is_expired() {
+                match serde_json::from_value(stored.response) {
+                    Ok(response) => return Ok(Some(response)),
+                    // e.g. the response type changed; log in again.
+                    Err(e) => info!("Ignoring unreadable stored response: {}", e),
+                }
+            } else if let Some(handle) = stored.refresh_handle {
+                match self.refresh(&handle) {
+                    Ok(session) => return self.save_session(store, profile, session).map(Some),
+                    Err(e) => info!("Could not refresh stored response: {}", e),
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether `granted` covers all the scopes this client asks for.
+    fn has_scopes(&self, granted: Option<&Vec<String>>) -> bool {
+        match (&self.scopes, granted) {
+            (None, _) => true,
+            (Some(wanted), Some(granted)) => wanted.iter().all(|s| granted.contains(s)),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Run the authn process, and save the result in `store` under `profile`.
+    pub fn authenticate_and_store<R>(&self, store: &dyn CredentialStore, profile: &str) -> Result<R, Error>
+        where R: 'static + DeserializeOwned + Serialize + Send
+    {
+        let session = self.session()?;
+        self.save_session(store, profile, session)
+    }
+
+    /// Save `session` in `store` under `profile`, returning the response.
+    fn save_session<R>(&self, store: &dyn CredentialStore, profile: &str, session: Session<R>) -> Result<R, Error>
+        where R: Serialize
+    {
+        let json = serde_json::to_value(&session.response)
+            .map_err(|e| Error::MalformedResponse(e.to_string()))?;
+        // Expire with the access token, if that is sooner.
+        let ttl = match (self.cache_ttl, session.expires_in) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let mut stored = StoredCredential::new(json, ttl);
+        stored.refresh_handle = session.refresh_handle;
+        stored.scopes = session.scopes;
+        store.save(&self.store_key(profile), &stored).map_err(Error::Store)?;
+        Ok(session.response)
+    }
+
+    /// Remove the stored response for `profile`, returning whether one existed.
+    pub fn logout(&self, store: &dyn CredentialStore, profile: &str) -> Result<bool, Error> {
+        store.remove(&self.store_key(profile)).map_err(Error::Store)
+    }
+
+    /// Run the authn process, returning the response `R` produced by
+    /// the proxy's session handler.
+    pub fn authenticate<R>(&self) -> Result<R, Error>
+        where R: 'static + DeserializeOwned + Serialize + Send
+    {
+        self.session().map(|session| session.response)
+    }
+
+    /// Run the authn process, returning the response along with
+    /// the details needed to `refresh` it later.
+    pub fn session<R>(&self) -> Result<Session<R>, Error>
+        where R: 'static + DeserializeOwned + Serialize + Send
+    {
+        if self.device_flow {
+            return self.device_session();
+        }
+        let delivery = self.delivery.unwrap_or_else(|| {
+            if loopback_unreachable() { Delivery::Headless } else { Delivery::Listener }
+        });
+        match delivery {
+            Delivery::Listener => self.authenticate_listener(),
+            Delivery::Headless => self.authenticate_headless(),
+            Delivery::Poll => self.authenticate_poll(),
+        }
+    }
+
+    /// Renew a session using the `refresh_handle` from an earlier
+    /// `Session`, without involving the `User`.
+    ///
+    /// Returns `Error::RefreshRejected` if the proxy no longer accepts
+    /// the handle, in which case the client should authenticate again.
+    pub fn refresh<R>(&self, refresh_handle: &str) -> Result<Session<R>, Error>
+        where R: 'static + DeserializeOwned + Serialize + Send
+    {
+        let token = CsrfToken::new_random();
+        let params = RefreshParams {
+            csrf_token: token.clone(),
+            refresh_handle: refresh_handle.to_string(),
+        };
+        let mut resp = self.http_client
+            .post(&format!("{}oauth-cli/refresh", self.proxy_url))
+            .json(&params)
+            .send()
+            .map_err(Error::ProxyUnreachable)?;
+        match resp.status() {
+            reqwest::StatusCode::OK => {
+                let resp: FinResponse<R> = resp.json()
+                    .map_err(|e| Error::MalformedResponse(e.to_string()))?;
+                verify_response(resp, &token, None)
+            },
+            reqwest::StatusCode::FORBIDDEN => Err(Error::AccessDenied),
+            reqwest::StatusCode::GONE => Err(Error::RefreshRejected),
+            status => Err(Error::ProxyStatus(status)),
+        }
+    }
+
+    /// Run the device authorization grant (RFC 8628), returning the
+    /// response `R` produced by the proxy's session handler.
+    ///
+    /// The proxy must have `device_flow` enabled.
+    pub fn authenticate_device<R>(&self) -> Result<R, Error>
+        where R: 'static + DeserializeOwned + Serialize + Send
+    {
+        self.device_session().map(|session| session.response)
+    }
+
+    fn device_session<R>(&self) -> Result<Session<R>, Error>
+        where R: 'static + DeserializeOwned + Serialize + Send
+    {
+        let deadline = self.options.timeout.map(|t| Instant::now() + t);
+        let token = CsrfToken::new_random();
+        let mut resp = self.http_client
+            .post(&format!("{}oauth-cli/device/start", self.proxy_url))
+            .json(&DeviceStartParams {
+                csrf_token: token.clone(),
+                provider: self.provider.clone(),
+                scopes: self.scopes.clone(),
+            })
+            .send()
+            .map_err(Error::ProxyUnreachable)?;
+        if resp.status() == reqwest::StatusCode::BAD_REQUEST && self.scopes.is_some() {
+            let text = resp.text().map_err(Error::ProxyUnreachable)?;
+            return Err(Error::ScopeRejected(text));
+        }
+        if !resp.status().is_success() {
+            return Err(Error::ProxyStatus(resp.status()));
+        }
+        let device: DeviceStartResponse = resp.json()
+            .map_err(|e| Error::MalformedResponse(e.to_string()))?;
+        self.presenter.present_device_code(&device.verification_uri, &device.user_code);
+        self.presenter.waiting();
+
+        let expires = Instant::now() + Duration::from_secs(device.expires_in);
+        let deadline = Some(deadline.map_or(expires, |d| d.min(expires)));
+        let mut interval = Duration::from_secs(device.interval);
+        let poll_url = format!("{}oauth-cli/device/poll", self.proxy_url);
+        let params = DevicePollParams {
+            csrf_token: token.clone(),
+            device_code: device.device_code,
+            provider: self.provider.clone(),
+            scopes: self.scopes.clone(),
+        };
+        loop {
+            self.sleep(interval, deadline)?;
+            let mut resp = self.http_client
+                .post(&poll_url)
+                .json(&params)
+                .send()
+                .map_err(Error::ProxyUnreachable)?;
+            match resp.status() {
+                reqwest::StatusCode::OK => {
+                    let resp: FinResponse<R> = resp.json()
+                        .map_err(|e| Error::MalformedResponse(e.to_string()))?;
+                    return verify_response(resp, &token, None);
+                },
+                reqwest::StatusCode::ACCEPTED => continue,
+                reqwest::StatusCode::TOO_MANY_REQUESTS => interval += Duration::from_secs(5),
+                reqwest::StatusCode::FORBIDDEN => return Err(Error::AccessDenied),
+                reqwest::StatusCode::GONE => return Err(Error::Timeout),
+                status => return Err(Error::ProxyStatus(status)),
+            }
+        }
+    }
+
+    /// Sleep for `duration`, returning early with an error if the
+    /// `deadline` passes or the authentication is cancelled.
+    fn sleep(&self, duration: Duration, deadline: Option<Instant>) -> Result<(), Error> {
+        let until = Instant::now() + duration;
+        let step = Duration::from_millis(POLL_INTERVAL_MS);
+        loop {
+            if self.options.cancel.as_ref().map_or(false, CancelHandle::is_cancelled) {
+                return Err(Error::Cancelled);
+            }
+            let now = Instant::now();
+            if deadline.map_or(false, |d| now >= d) {
+                return Err(Error::Timeout);
+            }
+            if now >= until {
+                return Ok(());
+            }
+            thread::sleep(step.min(until - now));
+        }
+    }
+
+    fn authenticate_poll<R>(&self) -> Result<Session<R>, Error>
+        where R: 'static + DeserializeOwned + Serialize + Send
+    {
+        let deadline = self.options.timeout.map(|t| Instant::now() + t);
+        let token = CsrfToken::new_random();
+        let key = ClientKey::generate().map_err(Error::KeyGeneration)?;
+        let client_key = key.public_key();
+        let params = GenParams {
+            client_port: None,
+            delivery: Delivery::Poll,
+            csrf_token: token.clone(),
+            provider: self.provider.clone(),
+            scopes: self.scopes.clone(),
+            client_key: Some(client_key.clone()),
+        };
+        let mut key = Some(key);
+        let url = self.get_authorization_url(&params)?;
+        self.presenter.present(&url);
+        self.presenter.waiting();
+
+        let poll_url = format!("{}oauth-cli/poll", self.proxy_url);
+        let params = PollParams {
+            csrf_token: token.clone(),
+            client_key: Some(client_key),
+        };
+        loop {
+            // Checked between polls, so may take up to one
+            // long-poll interval to take effect.
+            if self.options.cancel.as_ref().map_or(false, CancelHandle::is_cancelled) {
+                return Err(Error::Cancelled);
+            }
+            if deadline.map_or(false, |d| Instant::now() >= d) {
+                return Err(Error::Timeout);
+            }
+            let mut resp = self.http_client
+                .post(&poll_url)
+                .json(&params)
+                .send()
+                .map_err(Error::ProxyUnreachable)?;
+            match resp.status() {
+                reqwest::StatusCode::OK => {
+                    let resp: FinResponse<R> = resp.json()
+                        .map_err(|e| Error::MalformedResponse(e.to_string()))?;
+                    return verify_response(resp, &token, key.take());
+                },
+                reqwest::StatusCode::NO_CONTENT => continue,
+                status => return Err(Error::ProxyStatus(status)),
+            }
+        }
+    }
+
+    fn authenticate_headless<R>(&self) -> Result<Session<R>, Error>
+        where R: 'static + DeserializeOwned + Serialize + Send
+    {
+        let deadline = self.options.timeout.map(|t| Instant::now() + t);
+        let token = CsrfToken::new_random();
+        let key = ClientKey::generate().map_err(Error::KeyGeneration)?;
+        let params = GenParams {
+            client_port: None,
+            delivery: Delivery::Headless,
+            csrf_token: token.clone(),
+            provider: self.provider.clone(),
+            scopes: self.scopes.clone(),
+            client_key: Some(key.public_key()),
+        };
+        let url = self.get_authorization_url(&params)?;
+        self.presenter.present(&url);
+        self.presenter.prompt_code();
+
+        // Read on a separate thread, so the timeout and
+        // cancellation still apply.
+        let (tx, rx) = mpsc::sync_channel(1);
+        thread::spawn(move || {
+            let mut line = String::new();
+            let res = match io::stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => Err(Error::Cancelled),
+                Ok(_) => FinResponse::<R>::from_blob(&line)
+                    .map_err(|e| Error::MalformedResponse(e.to_string()))
+                    .and_then(|resp| verify_response(resp, &token, Some(key))),
+            };
+            let _ = tx.send(res);
+        });
+        wait_for_response(&rx, deadline, self.options.cancel.as_ref())
+    }
+
+    fn authenticate_listener<R>(&self) -> Result<Session<R>, Error>
+        where R: 'static + DeserializeOwned + Serialize + Send
+    {
+        let deadline = self.options.timeout.map(|t| Instant::now() + t);
+        let sys = actix::System::new("oauth_cli");  // <- create Actix system
+        let token = CsrfToken::new_random();
+        let key = ClientKey::generate().map_err(Error::KeyGeneration)?;
+        let client_key = key.public_key();
+        let (tx, rx) = mpsc::sync_channel(1);
+        let state = AppState {
+            nonce: Arc::new(token.clone()),
+            key: Arc::new(Mutex::new(Some(key))),
+            tx: Arc::new(tx),
+            success_page: self.success_page.clone().map(Arc::new),
+        };
+        let (port, server) = self.run_oauth_listener::<R>(state)?;
+        let params = GenParams {
+            client_port: Some(port),
+            delivery: Delivery::Listener,
+            csrf_token: token,
+            provider: self.provider.clone(),
+            scopes: self.scopes.clone(),
+            client_key: Some(client_key),
+        };
+        let url = self.get_authorization_url(&params)?;
+        self.presenter.present(&url);
+        self.presenter.waiting();
+
+        let current_system = actix::System::current();
+        let cancel = self.options.cancel.clone();
+        let result = Arc::new(Mutex::new(None));
+        let result2 = result.clone();
+        thread::spawn(move || {
+            let res = wait_for_response(&rx, deadline, cancel.as_ref());
+            actix::System::set_current(current_system.clone());
+            *result2.lock().unwrap() = Some(res);
+            server.do_send(actix_web::server::StopServer { graceful: false });
+            current_system.stop();
+        });
+        sys.run();  // <- Run actix system, this method starts all async processes
+
+        let res = result.lock().unwrap().take();
+        res.unwrap_or(Err(Error::Cancelled))
+    }
+
+    fn run_oauth_listener<R>(&self, state: AppState<R>) -> Result<(u16, Addr<Server>), Error>
+        where R: 'static + DeserializeOwned + Serialize + Send
+    {
+        let new_server = || {
+            let state = state.clone();
+            server::new(move || {
+                App::with_state(state.clone()).resource("/", |r| r.f(handle_response::<R>))
+            })
+            .workers(1)
+        };
+        let server = match self.port_range {
+            None => new_server().bind((self.bind_address, 0)),
+            Some((low, high)) => {
+                // Take the first free port in the range, reporting the
+                // last failure if none are available.
+                let mut res = Err(io::Error::new(io::ErrorKind::AddrInUse, "empty port range"));
+                for port in low..=high {
+                    res = new_server().bind((self.bind_address, port));
+                    if res.is_ok() {
+                        break;
+                    }
+                }
+                res
+            }
+        }.map_err(Error::Bind)?;
+        let port = server.addrs().get(0).expect("bound server has no address").port();
+
+        Ok((port, server.start()))
+    }
+
+    fn get_authorization_url(&self, params: &GenParams) -> Result<Url, Error> {
+        let mut resp = self.http_client
+            .post(&format!("{}{}", self.proxy_url, self.start_path))
+            .json(params)
+            .send()
+            .map_err(Error::ProxyUnreachable)?; 
+        info!("Response: {:#?}", resp);
+        if resp.status() == reqwest::StatusCode::BAD_REQUEST && self.scopes.is_some() {
+            let text = resp.text().map_err(Error::ProxyUnreachable)?;
+            return Err(Error::ScopeRejected(text));
+        }
+        if !resp.status().is_success() {
+            return Err(Error::ProxyStatus(resp.status()));
+        }
+        let text = resp.text().map_err(Error::ProxyUnreachable)?;
+        Url::parse(&text).map_err(|e| Error::MalformedResponse(e.to_string()))
+    }
+}
+
+/// Guess whether the `User`'s browser can reach a listener on our
+/// loopback interface. Over SSH, the browser runs on another machine.
+fn loopback_unreachable() -> bool {
+    ["SSH_CONNECTION", "SSH_CLIENT", "SSH_TTY"].iter()
+        .any(|var| env::var_os(var).is_some())
+}
+
+/// Checks the CSRF token in `resp` matches the one we sent, and
+/// opens the response (and refresh handle) with `key`, if we asked
+/// for it to be sealed.
+fn verify_response<R>(resp: FinResponse<R>, expected: &CsrfToken, key: Option<ClientKey>)
+    -> Result<Session<R>, Error>
+    where R: DeserializeOwned
+{
+    info!("Received nonce: {}, Expected nonce: {}", resp.csrf_token.secret(), expected.secret());
+    if &resp.csrf_token != expected {
+        return Err(Error::CsrfMismatch);
+    }
+    info!("CSRF tokens match");
+    let (response, refresh_handle) = match (key, resp.sealed) {
+        (Some(key), Some(sealed)) => {
+            let json = key.open(&sealed, expected.secret())
+                .map_err(|e| Error::MalformedResponse(e.to_string()))?;
+            let payload: SealedPayload<R> = serde_json::from_slice(&json)
+                .map_err(|e| Error::MalformedResponse(e.to_string()))?;
+            (payload.response, payload.refresh_handle)
+        },
+        // Having asked for a sealed response, refuse one in the clear.
+        (Some(_), None) => return Err(Error::MalformedResponse("the response was not sealed".to_string())),
+        (None, _) => {
+            let response = resp.response
+                .ok_or_else(|| Error::MalformedResponse("the response is missing".to_string()))?;
+            (response, resp.refresh_handle)
+        },
+    };
+    Ok(Session {
+        response,
+        refresh_handle,
+        expires_in: resp.expires_in.map(Duration::from_secs),
+        scopes: resp.scopes,
     })
-    .workers(1)
-    .bind("127.0.0.1:0")
-    .expect("Can not bind to 127.0.0.1:0");
-    let port = server.addrs().get(0).unwrap().port();
+}
+
+type ChannelMsg<R> = Result<Session<R>, Error>;
 
-    (port, server.start())
+/// Blocks until a response arrives on `rx`, the `deadline` passes,
+/// or `cancel` is triggered.
+fn wait_for_response<R>(
+    rx: &mpsc::Receiver<ChannelMsg<R>>,
+    deadline: Option<Instant>,
+    cancel: Option<&CancelHandle>,
+) -> ChannelMsg<R> {
+    let interval = Duration::from_millis(POLL_INTERVAL_MS);
+    loop {
+        if cancel.map_or(false, CancelHandle::is_cancelled) {
+            return Err(Error::Cancelled);
+        }
+        let wait = match deadline {
+            Some(d) => {
+                let now = Instant::now();
+                if now >= d {
+                    return Err(Error::Timeout);
+                }
+                interval.min(d - now)
+            },
+            None => interval,
+        };
+        match rx.recv_timeout(wait) {
+            Ok(res) => return res,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            // A closed channel means the listener went away before
+            // receiving anything.
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Err(Error::Cancelled),
+        }
+    }
+}
+
+struct AppState<R> {
+    // server_url: String,
+    nonce: Arc<CsrfToken>,
+    /// Taken by the first response to this login.
+    key: Arc<Mutex<Option<ClientKey>>>,
+    tx: Arc<mpsc::SyncSender<ChannelMsg<R>>>,
+    success_page: Option<Arc<String>>,
 }
 
-fn handle_response<R>((info, state): (Query<FinResponse<R>>, State<AppState>)) -> HttpResponse
-    where R: 'static + DeserializeOwned + Serialize
+impl<R> Clone for AppState<R> {
+    fn clone(&self) -> Self {
+        AppState {
+            nonce: self.nonce.clone(),
+            key: self.key.clone(),
+            tx: self.tx.clone(),
+            success_page: self.success_page.clone(),
+        }
+    }
+}
+
+fn handle_response<R>(req: &HttpRequest<AppState<R>>) -> HttpResponse
+    where R: 'static + DeserializeOwned + Serialize + Send
 {
-    // info!("Info: {:#?}", info);
-    let FinResponse { csrf_token, response, welcome_redirect } = info.into_inner();
-    info!("Received nonce: {}, Expected nonce: {}", csrf_token.secret(), state.nonce.secret());
-    // info!("new_secret: {}", new_secret);
-    if &csrf_token == state.nonce.deref() {
-        info!("CSRF tokens match");
-        state.tx.send((serde_json::to_string(&response).unwrap(), actix::System::current())).unwrap();
-    } else {
-        state.tx.send(("Incorrect tokens".to_string(), actix::System::current())).unwrap();
+    let state = req.state();
+    // Anything on this machine (or a web page in the browser) can make
+    // requests to the listener, so those without this login's state are
+    // turned away, rather than ending the login.
+    let expected = state.nonce.secret().as_str();
+    let matches = url::form_urlencoded::parse(req.query_string().as_bytes())
+        .any(|(key, value)| key == "state" && value == expected);
+    if !matches {
+        warn!("Ignoring request to the listener without the expected state");
+        return HttpResponse::BadRequest()
+            .connection_type(http::ConnectionType::Close)
+            .finish();
     }
+    let (res, welcome_redirect) = match serde_qs::from_str::<FinResponse<R>>(req.query_string()) {
+        Ok(resp) => {
+            let welcome_redirect = resp.welcome_redirect.clone();
+            let res = match state.key.lock().unwrap().take() {
+                Some(key) => verify_response(resp, state.nonce.deref(), Some(key)),
+                None => Err(Error::MalformedResponse("the response was already received".to_string())),
+            };
+            (res, welcome_redirect)
+        },
+        Err(e) => (Err(Error::MalformedResponse(e.to_string())), None),
+    };
+    // Only the first response is of interest; later ones (e.g. a
+    // browser retrying) are dropped.
+    let _ = state.tx.try_send(res);
 
-    if let Some(welcome) = welcome_redirect {
+    if let Some(ref page) = state.success_page {
+        HttpResponse::Ok()
+            .connection_type(http::ConnectionType::Close)
+            .content_type("text/html; charset=utf-8")
+            .body(page.to_string())
+    } else if let Some(welcome) = welcome_redirect {
         let html = super::get_redirect_page(welcome.deref(), welcome.deref());
         HttpResponse::Ok()
             .connection_type(http::ConnectionType::Close)
@@ -128,12 +925,41 @@ fn handle_response<R>((info, state): (Query<FinResponse<R>>, State<AppState>)) -
     }
 }
 
-fn get_authorization_url(params: &GenParams, server_url: Url) -> String {
-    // let server_url = &Config::get_global().server_url;
-    let client = reqwest::Client::new();
-    let resp = client.post(&format!("{}oauth-cli/start", server_url))
-                     .json(params)
-                     .send(); 
-    info!("Response: {:#?}", resp);
-    resp.unwrap().text().unwrap()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fin_response(state: &CsrfToken) -> FinResponse<String> {
+        FinResponse {
+            csrf_token: state.clone(),
+            response: Some("secret".to_string()),
+            sealed: None,
+            welcome_redirect: None,
+            refresh_handle: Some("handle".to_string()),
+            expires_in: None,
+            scopes: None,
+        }
+    }
+
+    #[test]
+    fn unsealed_response_is_refused_after_sending_a_key() {
+        let state = CsrfToken::new("state".to_string());
+        let key = ClientKey::generate().unwrap();
+        match verify_response(fin_response(&state), &state, Some(key)) {
+            Err(Error::MalformedResponse(msg)) => assert_eq!(msg, "the response was not sealed"),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("accepted an unsealed response"),
+        }
+    }
+
+    #[test]
+    fn sealed_response_is_opened() {
+        let state = CsrfToken::new("state".to_string());
+        let key = ClientKey::generate().unwrap();
+        let mut resp = fin_response(&state);
+        resp.seal(&key.public_key()).unwrap();
+        let session = verify_response(resp, &state, Some(key)).unwrap();
+        assert_eq!(session.response, "secret");
+        assert_eq!(session.refresh_handle, Some("handle".to_string()));
+    }
 }
@@ -0,0 +1,110 @@
+//! Ways of showing the authorization URL to the `User`.
+
+use qrcode::QrCode;
+use url::Url;
+
+/// Shows the authorization URL to the `User`, and keeps them
+/// informed while the client waits for the response.
+///
+/// Implementations should avoid writing to stdout, which callers
+/// may be using for machine-readable output.
+pub trait UrlPresenter: Send + Sync {
+    /// Show the authorization `url` to the `User`.
+    fn present(&self, url: &Url);
+
+    /// Called once the client is waiting for the `User` to finish
+    /// authorizing in the browser.
+    fn waiting(&self) {}
+
+    /// In headless mode, ask the `User` to paste the code shown
+    /// in the browser. The code is read from stdin.
+    fn prompt_code(&self) {
+        eprint!("Paste the code shown in your browser: ");
+    }
+
+    /// For the device flow, show the `User` the `user_code` to enter
+    /// at the verification `url`.
+    fn present_device_code(&self, url: &Url, user_code: &str) {
+        eprintln!("Enter the code {} when prompted.", user_code);
+        self.present(url);
+    }
+}
+
+/// Opens the URL in the default browser, falling back to printing it
+/// to stderr if no browser can be found.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Browser;
+
+impl UrlPresenter for Browser {
+    fn present(&self, url: &Url) {
+        eprintln!("Attempting to open URL in browser");
+        let failed = match open::that(url.to_string()) {
+                Ok(s) if s.success() => false,
+                Ok(_) => { eprintln!("Failed to find browser to open URL."); true },
+                Err(_) => { eprintln!("Couldn't find native 'open` command"); true },
+        };
+        if failed {
+            Stderr.present(url);
+        }
+    }
+
+    fn waiting(&self) {
+        Stderr.waiting();
+    }
+}
+
+/// Prints the URL to stderr for the `User` to copy and paste.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stderr;
+
+impl UrlPresenter for Stderr {
+    fn present(&self, url: &Url) {
+        eprintln!(
+            "Open this URL in your browser:\n{}\n",
+            url.to_string()
+        );
+    }
+
+    fn waiting(&self) {
+        eprintln!("Waiting to receive secret...");
+    }
+}
+
+/// Renders the URL as a QR code on stderr, for scanning with a phone.
+/// The URL itself is printed as well.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QrCodeTerminal;
+
+impl UrlPresenter for QrCodeTerminal {
+    fn present(&self, url: &Url) {
+        match QrCode::new(url.as_str()) {
+            Ok(code) => {
+                // Drawn inverted, so the code reads correctly on the
+                // usual dark terminal background.
+                let image = code.render::<char>()
+                    .dark_color(' ')
+                    .light_color('\u{2588}')
+                    .module_dimensions(2, 1)
+                    .build();
+                eprintln!("Scan this code to authorize:\n{}\n", image);
+            },
+            Err(e) => eprintln!("Could not render QR code: {}", e),
+        }
+        Stderr.present(url);
+    }
+
+    fn waiting(&self) {
+        Stderr.waiting();
+    }
+}
+
+/// Hands the URL to a closure, e.g. to display it in a GUI.
+pub struct Callback<F>(pub F);
+
+impl<F> UrlPresenter for Callback<F>
+    where F: Fn(&Url) + Send + Sync
+{
+    fn present(&self, url: &Url) {
+        (self.0)(url)
+    }
+}
@@ -0,0 +1,279 @@
+//! Persistent storage for responses from previous logins, so the
+//! `User` is not sent through the browser on every run.
+//!
+//! Entries are keyed by the proxy URL and a profile name, allowing
+//! several identities for the same proxy.
+//!
+//! With the `keyring` feature, `keyring::KeyringStore` keeps
+//! credentials in the OS keyring instead of a file.
+
+use failure::{format_err, Error};
+use serde_derive::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "keyring")]
+pub mod keyring;
+
+/// Identifies a stored credential.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StoreKey {
+    pub proxy_url: String,
+    pub profile: String,
+}
+
+impl StoreKey {
+    pub fn new(proxy_url: &str, profile: &str) -> Self {
+        StoreKey {
+            proxy_url: proxy_url.to_string(),
+            profile: profile.to_string(),
+        }
+    }
+
+    /// Flattened form, for backends which need a single string key.
+    pub fn to_string_key(&self) -> String {
+        format!("{}#{}", self.proxy_url, self.profile)
+    }
+}
+
+/// A response from the proxy, as stored.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StoredCredential {
+    /// The response `R` returned by the proxy, as JSON.
+    pub response: serde_json::Value,
+
+    /// Unix time at which the response was obtained.
+    pub obtained_at: u64,
+
+    /// Unix time after which the response should no longer be used.
+    pub expires_at: Option<u64>,
+
+    /// Handle for renewing the response with `Client::refresh`.
+    #[serde(default)]
+    pub refresh_handle: Option<String>,
+
+    /// Scopes granted to the response, if the proxy reported them.
+    #[serde(default)]
+    pub scopes: Option<Vec<String>>,
+}
+
+impl StoredCredential {
+    /// Wrap `response`, obtained now, and valid for `ttl` if given.
+    pub fn new(response: serde_json::Value, ttl: Option<Duration>) -> Self {
+        let now = unix_now();
+        StoredCredential {
+            response,
+            obtained_at: now,
+            expires_at: ttl.map(|ttl| now + ttl.as_secs()),
+            refresh_handle: None,
+            scopes: None,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.map_or(false, |expires_at| unix_now() >= expires_at)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Storage backend for credentials.
+pub trait CredentialStore {
+    /// Fetch the credential stored under `key`, if any.
+    fn load(&self, key: &StoreKey) -> Result<Option<StoredCredential>, Error>;
+
+    /// Store `credential` under `key`, replacing any existing entry.
+    fn save(&self, key: &StoreKey, credential: &StoredCredential) -> Result<(), Error>;
+
+    /// Remove the credential under `key`, returning whether one existed.
+    fn remove(&self, key: &StoreKey) -> Result<bool, Error>;
+}
+
+/// Stores credentials in a JSON file readable only by the user
+/// (mode `0600` on Unix).
+///
+/// The default location is `$XDG_CONFIG_HOME/olaf2/credentials.json`,
+/// falling back to `~/.config/olaf2/credentials.json`.
+#[derive(Clone, Debug)]
+pub struct FileStore {
+    path: PathBuf,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct FileContents {
+    entries: BTreeMap<String, StoredCredential>,
+}
+
+impl FileStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileStore { path: path.into() }
+    }
+
+    /// Store in the default location, under the XDG config directory.
+    pub fn default_location() -> Result<Self, Error> {
+        let base = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .ok_or_else(|| format_err!("could not find a config directory; set $HOME"))?;
+        Ok(Self::new(base.join("olaf2").join("credentials.json")))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read(&self) -> Result<FileContents, Error> {
+        match fs::read(&self.path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(FileContents::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write via a temporary file, created with restricted permissions,
+    /// which then replaces the original.
+    ///
+    /// A temporary file left over from an earlier run is removed first:
+    /// the mode only applies when the file is created, so reusing it
+    /// would keep whatever permissions it had.
+    fn write(&self, contents: &FileContents) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        if let Err(e) = fs::remove_file(&tmp) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(contents)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+impl CredentialStore for FileStore {
+    fn load(&self, key: &StoreKey) -> Result<Option<StoredCredential>, Error> {
+        Ok(self.read()?.entries.remove(&key.to_string_key()))
+    }
+
+    fn save(&self, key: &StoreKey, credential: &StoredCredential) -> Result<(), Error> {
+        let mut contents = self.read()?;
+        contents.entries.insert(key.to_string_key(), credential.clone());
+        self.write(&contents)
+    }
+
+    fn remove(&self, key: &StoreKey) -> Result<bool, Error> {
+        let mut contents = self.read()?;
+        let removed = contents.entries.remove(&key.to_string_key()).is_some();
+        if removed {
+            self.write(&contents)?;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A store in a fresh directory of its own, so tests do not see each
+    /// other's files.
+    fn store(test: &str) -> FileStore {
+        let dir = env::temp_dir().join(format!("olaf2-store-{}-{}", test, ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        FileStore::new(dir.join("credentials.json"))
+    }
+
+    fn cleanup(store: &FileStore) {
+        fs::remove_dir_all(store.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let store = store("round-trip");
+        let key = StoreKey::new("http://proxy.example.com/", "default");
+        assert!(store.load(&key).unwrap().is_none());
+        assert!(!store.remove(&key).unwrap());
+
+        let mut credential = StoredCredential::new(json!({"secret": "s3cret"}), None);
+        credential.refresh_handle = Some("handle".to_string());
+        credential.scopes = Some(vec!["read:user".to_string()]);
+        store.save(&key, &credential).unwrap();
+
+        let loaded = store.load(&key).unwrap().expect("credential was not stored");
+        assert_eq!(loaded.response, credential.response);
+        assert_eq!(loaded.obtained_at, credential.obtained_at);
+        assert_eq!(loaded.refresh_handle, credential.refresh_handle);
+        assert_eq!(loaded.scopes, credential.scopes);
+
+        store.save(&key, &StoredCredential::new(json!("new"), None)).unwrap();
+        assert_eq!(store.load(&key).unwrap().unwrap().response, json!("new"));
+
+        assert!(store.remove(&key).unwrap());
+        assert!(store.load(&key).unwrap().is_none());
+        assert!(!store.remove(&key).unwrap());
+        cleanup(&store);
+    }
+
+    #[test]
+    fn profiles_and_proxies_are_kept_apart() {
+        let store = store("kept-apart");
+        let work = StoreKey::new("http://proxy.example.com/", "work");
+        let home = StoreKey::new("http://proxy.example.com/", "home");
+        let other = StoreKey::new("http://other.example.com/", "work");
+        store.save(&work, &StoredCredential::new(json!("work"), None)).unwrap();
+        store.save(&home, &StoredCredential::new(json!("home"), None)).unwrap();
+        store.save(&other, &StoredCredential::new(json!("other"), None)).unwrap();
+        assert_eq!(store.load(&work).unwrap().unwrap().response, json!("work"));
+        assert_eq!(store.load(&home).unwrap().unwrap().response, json!("home"));
+        assert_eq!(store.load(&other).unwrap().unwrap().response, json!("other"));
+
+        assert!(store.remove(&work).unwrap());
+        assert!(store.load(&work).unwrap().is_none());
+        assert_eq!(store.load(&home).unwrap().unwrap().response, json!("home"));
+        assert_eq!(store.load(&other).unwrap().unwrap().response, json!("other"));
+        cleanup(&store);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let store = store("private");
+        let key = StoreKey::new("http://proxy.example.com/", "default");
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        store.save(&key, &StoredCredential::new(json!("first"), None)).unwrap();
+        assert_eq!(mode(store.path()), 0o600);
+
+        // A readable temporary file left behind by an earlier run.
+        let tmp = store.path().with_extension("json.tmp");
+        fs::write(&tmp, b"stale").unwrap();
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o644)).unwrap();
+
+        store.save(&key, &StoredCredential::new(json!("second"), None)).unwrap();
+        assert_eq!(mode(store.path()), 0o600);
+        assert!(!tmp.exists());
+        assert_eq!(store.load(&key).unwrap().unwrap().response, json!("second"));
+        cleanup(&store);
+    }
+}
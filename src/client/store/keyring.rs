@@ -0,0 +1,141 @@
+//! Credential storage in the freedesktop Secret Service (e.g. GNOME
+//! Keyring or KWallet), accessed over D-Bus.
+//!
+//! Requires the `keyring` cargo feature.
+
+use failure::{format_err, Error};
+use secret_service::{EncryptionType, SecretService};
+
+use super::{CredentialStore, StoreKey, StoredCredential};
+
+/// Attribute identifying items created by this crate.
+const APPLICATION: &str = "olaf2";
+
+/// Stores credentials as items in the default Secret Service
+/// collection, one item per `StoreKey`.
+#[derive(Clone, Debug, Default)]
+pub struct KeyringStore;
+
+impl KeyringStore {
+    pub fn new() -> Self {
+        KeyringStore
+    }
+
+    fn attributes(key: &StoreKey) -> Vec<(&str, &str)> {
+        vec![
+            ("application", APPLICATION),
+            ("proxy_url", &key.proxy_url),
+            ("profile", &key.profile),
+        ]
+    }
+}
+
+/// Secret Service errors carry D-Bus types which are not `Sync`,
+/// so are converted to strings.
+fn ss_err<E: ::std::fmt::Display>(e: E) -> Error {
+    format_err!("secret service: {}", e)
+}
+
+impl CredentialStore for KeyringStore {
+    fn load(&self, key: &StoreKey) -> Result<Option<StoredCredential>, Error> {
+        let ss = SecretService::new(EncryptionType::Dh).map_err(ss_err)?;
+        let items = ss.search_items(Self::attributes(key)).map_err(ss_err)?;
+        let item = match items.first() {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        item.unlock().map_err(ss_err)?;
+        let secret = item.get_secret().map_err(ss_err)?;
+        Ok(Some(serde_json::from_slice(&secret)?))
+    }
+
+    fn save(&self, key: &StoreKey, credential: &StoredCredential) -> Result<(), Error> {
+        let ss = SecretService::new(EncryptionType::Dh).map_err(ss_err)?;
+        let collection = ss.get_default_collection().map_err(ss_err)?;
+        collection.unlock().map_err(ss_err)?;
+        let secret = serde_json::to_vec(credential)?;
+        collection.create_item(
+            &format!("olaf2 credentials for {} ({})", key.proxy_url, key.profile),
+            Self::attributes(key),
+            &secret,
+            true, // replace any existing item with the same attributes
+            "application/json",
+        ).map_err(ss_err)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &StoreKey) -> Result<bool, Error> {
+        let ss = SecretService::new(EncryptionType::Dh).map_err(ss_err)?;
+        let items = ss.search_items(Self::attributes(key)).map_err(ss_err)?;
+        for item in &items {
+            item.delete().map_err(ss_err)?;
+        }
+        Ok(!items.is_empty())
+    }
+}
+
+/// These use the Secret Service on the session bus, so only run with the
+/// `keyring-tests` feature. To keep the `User`'s own keyring out of it,
+/// run them in a throwaway session with its own (empty) keyring:
+///
+/// ```sh
+/// dbus-run-session -- sh -c \
+///     'echo -n | gnome-keyring-daemon --unlock --components=secrets \
+///      && cargo test --features keyring-tests'
+/// ```
+#[cfg(all(test, feature = "keyring-tests"))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A key unique to `test`, so tests do not see each other's items.
+    fn key(test: &str) -> StoreKey {
+        StoreKey::new("http://keyring-test.invalid/", &format!("{}-{}", test, ::std::process::id()))
+    }
+
+    #[test]
+    fn round_trip() {
+        let store = KeyringStore::new();
+        let key = key("round-trip");
+        assert!(store.load(&key).unwrap().is_none());
+
+        let mut credential = StoredCredential::new(json!({"secret": "s3cret"}), None);
+        credential.refresh_handle = Some("handle".to_string());
+        credential.scopes = Some(vec!["read:user".to_string()]);
+        store.save(&key, &credential).unwrap();
+
+        let loaded = store.load(&key).unwrap().expect("credential was not stored");
+        assert_eq!(loaded.response, credential.response);
+        assert_eq!(loaded.obtained_at, credential.obtained_at);
+        assert_eq!(loaded.refresh_handle, credential.refresh_handle);
+        assert_eq!(loaded.scopes, credential.scopes);
+
+        assert!(store.remove(&key).unwrap());
+        assert!(store.load(&key).unwrap().is_none());
+        assert!(!store.remove(&key).unwrap());
+    }
+
+    #[test]
+    fn save_replaces() {
+        let store = KeyringStore::new();
+        let key = key("save-replaces");
+        store.save(&key, &StoredCredential::new(json!("old"), None)).unwrap();
+        store.save(&key, &StoredCredential::new(json!("new"), None)).unwrap();
+        assert_eq!(store.load(&key).unwrap().unwrap().response, json!("new"));
+        assert!(store.remove(&key).unwrap());
+        assert!(store.load(&key).unwrap().is_none());
+    }
+
+    #[test]
+    fn profiles_are_kept_apart() {
+        let store = KeyringStore::new();
+        let (work, home) = (key("profiles-work"), key("profiles-home"));
+        store.save(&work, &StoredCredential::new(json!("work"), None)).unwrap();
+        store.save(&home, &StoredCredential::new(json!("home"), None)).unwrap();
+        assert_eq!(store.load(&work).unwrap().unwrap().response, json!("work"));
+
+        assert!(store.remove(&home).unwrap());
+        assert_eq!(store.load(&work).unwrap().unwrap().response, json!("work"));
+        assert!(store.remove(&work).unwrap());
+    }
+}
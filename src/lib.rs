@@ -4,6 +4,8 @@
 //! Note that OAuth 2.0 is an _authorization_ (authz) protocol, not 
 //! an authentication (authn) protocol. OpenID Connect is designed 
 //! as an authn protocol on top of OAuth 2.0, but is not widely deployed.
+//! Where it is, use the `Oidc` provider: the proxy then validates the
+//! `id_token` issued for each login.
 //!
 //! Olaf2 provides an form of authentication by authorizing the
 //! proxy server to retrieve the end-user's identity.
@@ -21,7 +23,8 @@
 //!
 //! The "Server" refers to the OAuth 2.0 authorization server, 
 //! and is implemented externally to Olaf2. We provide some
-//! preconfigured servers to connect to.
+//! preconfigured servers to connect to, and others can be added
+//! by implementing `server::OAuthProvider`.
 //! 
 //! The "Proxy" refers to the OAuth 2.0 client, which receives the
 //! authn and authz from the Server.
@@ -49,29 +52,49 @@
 //! oauth_provider = "Github"
 //! scopes = ["read:user", "user:email", "read:org"]
 //! welcome_redirect = "http://localhost:8080/"
+//!
+//! # Or, for an OpenID Connect provider:
+//! # [oauth_provider.Oidc]
+//! # issuer = "https://accounts.example.com"
+//! # See the `server` module for the other built-in providers.
+//! # Further providers can be served as `[providers.<name>]`,
+//! # see `proxy::Config`.
+//!
+//! # Optionally, only let in members of an organization.
+//! [access]
+//! allowed_orgs = ["my-org"]
 //! ```
 //! Run the proxy with
 //! 
 //! ```rust
 //!	use olaf2::*;
-//! use toml;
 //!
-//! // the lazy way to load the config...
-//! let toml = include_str!("proxy_config.toml");
-//! let config: proxy::Config = toml::from_str(toml).unwrap();
+//! // Reads the file, then applies any `OLAF2_*` environment
+//! // variables, e.g. `OLAF2_CLIENT_SECRET`.
+//! let config = proxy::Config::load(Some("proxy_config.toml"), &[]).unwrap();
 //! proxy::run_with(config, |token| {
 //!  	// This simple function prints the access token and
 //!		// returns it to the client
 //! 	let secret = token.secret().to_string();
 //! 	println!("Received token: {}", &secret);
 //! 	Ok(secret)
-//! });
+//! }).unwrap();
 //! ```
 //!
+//! or with the bundled binary: `olaf2 server --config proxy_config.toml`.
+//!
 //! In this example, we are creating a session token handler
 //! which simply prints the access token and returns the `String`
 //! to the client. 
 //!
+//! The handler receives a `proxy::TokenInfo`, which dereferences to
+//! the access token, and also carries the expiry, refresh token and
+//! the scopes the `User` actually granted.
+//!
+//! `TokenInfo::identity` holds the `User`'s `server::Identity`, as
+//! recovered from the provider. For a `Custom` provider, set its
+//! `userinfo_url` to an OpenID Connect style userinfo endpoint.
+//!
 //! On the other end of the connection, we need to tell the client
 //! to accept a `String`.
 //!
@@ -79,9 +102,17 @@
 //!
 //! Simply run the client with:
 //! ```rust
-//! let secret = client::authenticate::<String>("http://127.0.0.1:8081");
+//! let secret = client::authenticate::<String>("http://127.0.0.1:8081")
+//!     .expect("authentication failed");
 //! println!("Secret: {}", secret); 
 //! ```
+//!
+//! `authenticate` returns a `client::Error` describing what went wrong,
+//! for example if the proxy is unreachable or the CSRF token does not
+//! match.
+//!
+//! For more control (listener address, timeouts, how the URL is shown
+//! to the user, ...) use the `client::Client` builder.
 //! 
 //! Note: the proxy server returns a `String` from the closure, so we
 //! specify the same type parameter for `authenticate`.
@@ -91,16 +122,18 @@
 //! The protocol flow works as follows:
 //! 
 //! 1. The `Client` starts a local HTTP server on a random port.
-//! makes a get request to `proxy_url`, including the port number
-//! and a random nonce.
+//! makes a get request to `proxy_url`, including the port number,
+//! a random nonce, and an ephemeral X25519 public key.
 //!
-//! The `Proxy` server returns an OAuth 2.0 authz request URL.
+//! The `Proxy` server records the pending login under the nonce,
+//! and returns an OAuth 2.0 authz request URL.
 //!
 //! 2. The `User` visits this URL (the `Server`) ito authorize the request.
 //!    This includes a URL redirection back to the `Proxy`.
 //!    
 //! 3. The redirected request should contain the authz code
-//!    from the OAuth 2.0 `Server`.
+//!    from the OAuth 2.0 `Server`, and the nonce, which must
+//!    match a pending login.
 //!
 //! 4. The `Proxy` exchanges the authz code for an access token.
 //!    This token can be used to access `Server` resources
@@ -116,6 +149,14 @@
 //!    HTTP server being run by the `Client`, the session secret
 //!    is captured, and the `Client` can continue in authenticated mode.
 //!
+//!    In headless mode (e.g. over SSH, where the browser cannot reach
+//!    the `Client`), the `Proxy` instead shows the response as a code
+//!    which the `User` pastes into the `Client`.
+//!
+//!    Either way, the session secret is sealed to the `Client`'s key
+//!    from step 1, so it is unreadable in the browser's history, or
+//!    to another local process which answers on the port.
+//!
 //! ### Diagram
 //! ```
 //!                        4. Exchange authz code from (3)
@@ -138,7 +179,7 @@
 //! Response:            | CLI Application  |       Response: AuthURL
 //! redirect-to          | ("Client"/"User")|
 //! GET /oauth-cli/finish+---+----+---------+
-//! ?code=...&port=...       |    ^
+//! ?code=...&state=...      |    ^
 //!                          |    |
 //!                          +----+
 //!                     6. GET localhost:<port>/
@@ -154,7 +195,10 @@ pub mod client;
 pub mod proxy;
 pub mod server;
 mod msgs;
+mod seal;
 mod util;
+#[cfg(test)]
+mod mock_server;
 
 use url::Url;
 
@@ -167,4 +211,51 @@ pub(crate) fn get_redirect_page(redirect_url: &Url, welcome_redirect: &Url) -> S
     let html = html.replace("{{combined_url}}", &combined_url.to_string());
 
     html
+}
+
+pub(crate) fn get_headless_page(code: &str, welcome_redirect: &Url) -> String {
+    let html = include_str!("headless_template.html");
+    let html = html.replace("{{code}}", code);
+    let html = html.replace("{{welcome_url}}", &welcome_redirect.to_string());
+
+    html
+}
+
+/// `title` and `message` may include values from the request,
+/// so are escaped.
+pub(crate) fn get_error_page(title: &str, message: &str) -> String {
+    let html = include_str!("error_template.html");
+    let html = html.replace("{{title}}", &escape_html(title));
+    let html = html.replace("{{message}}", &escape_html(message));
+
+    html
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_page_is_escaped() {
+        let html = get_error_page("<b>Denied</b>", "user \"<script>alert('x')</script>\" & co");
+        assert!(!html.contains("<script>"));
+        assert!(!html.contains("<b>"));
+        assert!(html.contains("&lt;b&gt;Denied&lt;/b&gt;"));
+        assert!(html.contains("user &quot;&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;&quot; &amp; co"));
+    }
 }
\ No newline at end of file
@@ -4,35 +4,274 @@ extern crate olaf2;
 extern crate oauth2;
 extern crate toml;
 
-use actix::prelude::*;
 use failure::Error;
 use oauth2::prelude::SecretNewType;   
 use olaf2::*;
+use olaf2::client::store::{CredentialStore, FileStore, StoredCredential};
+use serde_json::{json, Value};
 
+use std::collections::HashSet;
 use std::env;
+use std::process;
+
+const USAGE: &str = "\
+Usage: olaf2 <command> [options]
+
+Commands:
+  serve [--config <path>] [--<key> <value>]...
+                      Run the proxy server. Other flags override
+                      configuration values, e.g. --port 8081.
+  login --proxy <url> [--profile <name>] [--provider <name>]
+        [--scopes <scope,...>] [--headless | --poll | --device]
+                      Authenticate against the proxy, caching the result.
+  token [--proxy <url>] [--profile <name>] [--provider <name>]
+                      Print the cached secret.
+  status [--proxy <url>] [--profile <name>] [--provider <name>]
+                      Show whether a cached secret exists.
+  logout [--proxy <url>] [--profile <name>] [--provider <name>]
+                      Remove the cached secret.
+
+Options:
+  --json              Print machine-readable output.
+
+The proxy URL defaults to $OLAF2_PROXY, the profile to \"default\".
+--provider picks one of the proxy's named providers, rather than
+its default one. --scopes asks for scopes beyond those configured
+at the proxy, which must allow them.
+When built with the `keyring` feature, credentials are kept in the
+OS keyring; set OLAF2_STORE=file to use a file instead.";
+
+/// Exit codes
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_NOT_LOGGED_IN: i32 = 3;
 
 fn main() {
 	env_logger::init();
 
+	let args: Vec<String> = env::args().skip(1).collect();
+	let command = args.first().map(String::as_str).unwrap_or("");
+	let bool_flags = ["json", "headless", "poll", "device"];
+	let opts = match Args::parse(&args.get(1..).unwrap_or(&[]), &bool_flags) {
+		Ok(opts) => opts,
+		Err(e) => usage_error(&e),
+	};
+	let json = opts.flag("json");
+
+	let res = match command {
+		// `server` is kept for compatibility.
+		"serve" | "server" => serve(opts),
+		"login" => opts.only(&["proxy", "profile", "provider", "scopes"]).and_then(|_| login(&opts)),
+		"token" => opts.only(&["proxy", "profile", "provider"]).and_then(|_| token(&opts)),
+		"status" => opts.only(&["proxy", "profile", "provider"]).and_then(|_| status(&opts)),
+		"logout" => opts.only(&["proxy", "profile", "provider"]).and_then(|_| logout(&opts)),
+		"" | "help" | "--help" => {
+			println!("{}", USAGE);
+			Ok(0)
+		},
+		other => usage_error(&format!("unknown command `{}`", other)),
+	};
+	match res {
+		Ok(code) => process::exit(code),
+		Err(e) => {
+			if json {
+				println!("{}", json!({ "error": e.to_string() }));
+			}
+			eprintln!("Error: {}", e);
+			process::exit(EXIT_FAILURE);
+		}
+	}
+}
+
+fn usage_error(msg: &str) -> ! {
+	eprintln!("Error: {}\n\n{}", msg, USAGE);
+	process::exit(EXIT_USAGE);
+}
+
+/// Parsed `--key value` options and boolean `--flag`s.
+struct Args {
+	flags: HashSet<String>,
+	values: Vec<(String, String)>,
+}
+
+impl Args {
+	fn parse(args: &[String], bool_flags: &[&str]) -> Result<Args, String> {
+		let mut flags = HashSet::new();
+		let mut values = Vec::new();
+		let mut args = args.iter();
+		while let Some(arg) = args.next() {
+			if !arg.starts_with("--") {
+				return Err(format!("unexpected argument `{}`", arg));
+			}
+			let key = &arg[2..];
+			if bool_flags.contains(&key) {
+				flags.insert(key.to_string());
+			} else {
+				let value = args.next()
+					.ok_or_else(|| format!("missing value for `{}`", arg))?;
+				values.push((key.to_string(), value.to_string()));
+			}
+		}
+		Ok(Args { flags, values })
+	}
+
+	fn flag(&self, name: &str) -> bool {
+		self.flags.contains(name)
+	}
+
+	fn value(&self, name: &str) -> Option<&str> {
+		self.values.iter().rev()
+			.find(|(k, _)| k == name)
+			.map(|(_, v)| v.as_str())
+	}
+
+	/// Reject any `--key value` options other than `allowed`.
+	fn only(&self, allowed: &[&str]) -> Result<(), Error> {
+		match self.values.iter().find(|(k, _)| !allowed.contains(&k.as_str())) {
+			Some((k, _)) => usage_error(&format!("unknown option `--{}`", k)),
+			None => Ok(()),
+		}
+	}
+
+	fn proxy(&self) -> String {
+		match self.value("proxy").map(str::to_string).or_else(|| env::var("OLAF2_PROXY").ok()) {
+			Some(proxy) => proxy,
+			None => usage_error("no proxy given; use --proxy or set OLAF2_PROXY"),
+		}
+	}
 
-	match env::args().nth(1) {
-		Some(ref s) if s == "server" => server_main(),
-		Some(ref c) if c == "client" => client_main(),
-		_ => eprintln!("Usage: olaf2 (client|server)"),
+	fn profile(&self) -> String {
+		self.value("profile").unwrap_or("default").to_string()
+	}
+
+	/// Client for the options' proxy and provider.
+	fn client(&self) -> Result<client::Client, Error> {
+		let client = client::Client::new(&self.proxy())?;
+		Ok(match self.value("provider") {
+			Some(provider) => client.provider(provider),
+			None => client,
+		})
 	}
 }
 
-fn server_main() {
-	let toml = include_str!("proxy_config.toml");
-	let config: proxy::Config = toml::from_str(toml).unwrap();
+fn serve(opts: Args) -> Result<i32, Error> {
+	let path = opts.value("config").map(str::to_string)
+		.or_else(|| env::var("OLAF2_CONFIG").ok());
+	let overrides: Vec<_> = opts.values.into_iter()
+		.filter(|(k, _)| k != "config")
+		.collect();
+	let config = proxy::Config::load(path, &overrides)?;
 	proxy::run_with(config, |token| {
-		let secret = token.secret().to_string();
-		println!("Received token: {}", &secret);
-		Ok(secret)
-	});
+		Ok(token.secret().to_string())
+	})?;
+	Ok(0)
+}
+
+fn login(opts: &Args) -> Result<i32, Error> {
+	let (proxy, profile) = (opts.proxy(), opts.profile());
+	let store = credential_store()?;
+	let client = opts.client()?;
+	let client = if opts.flag("headless") {
+		client.delivery(client::Delivery::Headless)
+	} else if opts.flag("poll") {
+		client.delivery(client::Delivery::Poll)
+	} else {
+		client
+	};
+	let client = match opts.value("scopes") {
+		Some(scopes) => {
+			let scopes: Vec<&str> = scopes.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+			client.scopes(&scopes)
+		},
+		None => client,
+	};
+	let client = client.device_flow(opts.flag("device"));
+	let response: Value = client.authenticate_and_store(&*store, &profile)?;
+
+	if opts.flag("json") {
+		println!("{}", json!({ "proxy": proxy, "profile": profile, "response": response }));
+	} else {
+		eprintln!("Logged in to {} (profile {})", proxy, profile);
+	}
+	Ok(0)
+}
+
+/// The credential store to use: the OS keyring if available,
+/// otherwise a file.
+fn credential_store() -> Result<Box<dyn CredentialStore>, Error> {
+	#[cfg(feature = "keyring")]
+	{
+		if env::var("OLAF2_STORE").ok().as_ref().map(String::as_str) != Some("file") {
+			return Ok(Box::new(client::store::keyring::KeyringStore::new()));
+		}
+	}
+	Ok(Box::new(FileStore::default_location()?))
+}
+
+/// Look up the stored credential for the options' proxy and profile,
+/// refreshing it first if it has expired.
+fn stored(opts: &Args) -> Result<Option<StoredCredential>, Error> {
+	let client = opts.client()?;
+	let store = credential_store()?;
+	client.refresh_cached::<Value>(&*store, &opts.profile())?;
+	let stored = store.load(&client.store_key(&opts.profile()))?;
+	Ok(stored.filter(|s| !s.is_expired()))
+}
+
+fn token(opts: &Args) -> Result<i32, Error> {
+	match stored(opts)? {
+		Some(entry) => {
+			if opts.flag("json") {
+				println!("{}", json!({ "token": entry.response }));
+			} else {
+				match entry.response {
+					Value::String(ref s) => println!("{}", s),
+					ref other => println!("{}", other),
+				}
+			}
+			Ok(0)
+		},
+		None => {
+			if opts.flag("json") {
+				println!("{}", json!({ "token": null }));
+			} else {
+				eprintln!("Not logged in; run `olaf2 login` first");
+			}
+			Ok(EXIT_NOT_LOGGED_IN)
+		},
+	}
 }
 
-fn client_main() {
-	let secret = client::authenticate::<String>("http://127.0.0.1:8081");
-	println!("Secret: {}", secret);
+fn status(opts: &Args) -> Result<i32, Error> {
+	let (proxy, profile) = (opts.proxy(), opts.profile());
+	let entry = stored(opts)?;
+	if opts.flag("json") {
+		println!("{}", json!({
+			"proxy": proxy,
+			"profile": profile,
+			"logged_in": entry.is_some(),
+			"obtained_at": entry.as_ref().map(|e| e.obtained_at),
+			"expires_at": entry.as_ref().and_then(|e| e.expires_at),
+		}));
+	} else {
+		match entry {
+			Some(ref e) => println!("Logged in to {} (profile {}) since {} (unix time)", proxy, profile, e.obtained_at),
+			None => println!("Not logged in to {} (profile {})", proxy, profile),
+		}
+	}
+	Ok(if entry.is_some() { 0 } else { EXIT_NOT_LOGGED_IN })
+}
+
+fn logout(opts: &Args) -> Result<i32, Error> {
+	let (proxy, profile) = (opts.proxy(), opts.profile());
+	let store = credential_store()?;
+	let removed = opts.client()?.logout(&*store, &profile)?;
+	if opts.flag("json") {
+		println!("{}", json!({ "proxy": proxy, "profile": profile, "removed": removed }));
+	} else if removed {
+		eprintln!("Logged out of {} (profile {})", proxy, profile);
+	} else {
+		eprintln!("Not logged in to {} (profile {})", proxy, profile);
+	}
+	Ok(0)
 }
@@ -0,0 +1,127 @@
+//! A minimal HTTP server, standing in for providers in tests.
+
+use url::Url;
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A request received by the `MockServer`.
+#[derive(Clone, Debug)]
+pub(crate) struct Request {
+    pub method: String,
+    /// Path, without the query string.
+    pub path: String,
+    /// Headers, with lowercase names.
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl Request {
+    /// The body, parsed as an `application/x-www-form-urlencoded` form.
+    pub fn form(&self) -> HashMap<String, String> {
+        url::form_urlencoded::parse(self.body.as_bytes()).into_owned().collect()
+    }
+}
+
+/// Serves fixed JSON bodies by path, recording each request.
+/// Other paths get `404 Not Found`.
+pub(crate) struct MockServer {
+    url: Url,
+    routes: Arc<Mutex<HashMap<String, String>>>,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl MockServer {
+    /// Start serving `routes`, given as `(path, body)` pairs.
+    pub fn start(routes: &[(&str, &str)]) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("could not bind mock server");
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let routes: HashMap<String, String> = routes.iter()
+            .map(|(path, body)| (path.to_string(), body.to_string()))
+            .collect();
+        let routes = Arc::new(Mutex::new(routes));
+        let served = routes.clone();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                if let Some(request) = read_request(&stream) {
+                    let body = served.lock().unwrap().get(&request.path).cloned();
+                    // Recorded before responding, so the request is
+                    // visible as soon as the client has its response.
+                    recorded.lock().unwrap().push(request);
+                    respond(stream, body);
+                }
+            }
+        });
+        MockServer { url, routes, requests }
+    }
+
+    /// Serve `body` at `path` too, e.g. for a body which
+    /// refers to the server's own URL.
+    pub fn route(&self, path: &str, body: &str) {
+        self.routes.lock().unwrap().insert(path.to_string(), body.to_string());
+    }
+
+    /// URL of `path` on the server.
+    pub fn url(&self, path: &str) -> Url {
+        self.url.join(path).unwrap()
+    }
+
+    /// The requests received so far, oldest first.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?;
+    let path = target.split('?').next().unwrap_or(target).to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let mut header = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (header.next(), header.next()) {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    let len = headers.get("content-length").and_then(|len| len.parse().ok()).unwrap_or(0);
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).ok()?;
+    Some(Request {
+        method,
+        path,
+        headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+fn respond(mut stream: TcpStream, body: Option<String>) {
+    let (status, body) = match body {
+        Some(body) => ("200 OK", body),
+        None => ("404 Not Found", "{}".to_string()),
+    };
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body,
+    );
+}
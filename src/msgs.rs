@@ -1,3 +1,5 @@
+use failure::Error;
+use oauth2::prelude::*;
 use oauth2::{AuthorizationCode, CsrfToken};
 use serde::{de::DeserializeOwned, Serialize};
 use serde_derive::{Deserialize, Serialize};
@@ -6,15 +8,62 @@ use url_serde::Serde;
 
 use std::fmt::Debug;
 
+use crate::seal;
 use crate::util::*;
 
+/// How the final `FinResponse` is delivered back to the client.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all="lowercase")]
+pub enum Delivery {
+    /// Redirect the browser to the client listening on `client_port`.
+    Listener,
+    /// Show the response in the browser, for the user to paste
+    /// into the client (e.g. when running over SSH).
+    Headless,
+    /// Hold the response at the proxy until the client collects
+    /// it from `/oauth-cli/poll`.
+    Poll,
+}
+
+impl Default for Delivery {
+    fn default() -> Self {
+        Delivery::Listener
+    }
+}
+
 /// Parameters sent from client -> proxy server 
 /// on initial generate OAuth2 query.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct GenParams {
     #[serde(with="serde_secret_newtype", rename="state")]
     pub csrf_token: CsrfToken,
-    pub client_port: u16,
+    pub client_port: Option<u16>,
+    #[serde(default)]
+    pub delivery: Delivery,
+    /// Name of the provider to log in with, as configured in
+    /// `proxy::Config::providers`. `None` for the default provider.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub provider: Option<String>,
+    /// Scopes to request in addition to the proxy's configured ones.
+    /// Each must be allowed by the proxy's `allowed_scopes`.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub scopes: Option<Vec<String>>,
+    /// Ephemeral X25519 public key (base64url), to which the proxy
+    /// seals the response. See `FinResponse::sealed`.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub client_key: Option<String>,
+}
+
+/// Parameters sent from client -> proxy server
+/// to collect the response with `Delivery::Poll`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PollParams {
+    #[serde(with="serde_secret_newtype", rename="state")]
+    pub csrf_token: CsrfToken,
+    /// The `client_key` sent in `GenParams`. The `state` is in the
+    /// authorization URL, so is not enough to collect the response.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub client_key: Option<String>,
 }
 
 /// Parameters sent from OAuth2 server back
@@ -24,17 +73,177 @@ pub struct GenParams {
 pub struct FinParams {
     #[serde(with="serde_secret_newtype", rename="state")]
     pub csrf_token: CsrfToken,
-    pub client_port: u16,
 	#[serde(with="serde_secret_newtype")]
 	pub code: AuthorizationCode,
 }
 
+/// Parameters sent from client -> proxy server
+/// to start a device authorization grant (RFC 8628).
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeviceStartParams {
+    #[serde(with="serde_secret_newtype", rename="state")]
+    pub csrf_token: CsrfToken,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub provider: Option<String>,
+    /// As `GenParams::scopes`.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub scopes: Option<Vec<String>>,
+}
+
+/// Device authorization response from the OAuth2 server,
+/// forwarded by the proxy server to the client.
+/// See [RFC 8628 section 3.2](https://tools.ietf.org/html/rfc8628#section-3.2).
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeviceStartResponse {
+    pub device_code: String,
+    pub user_code: String,
+    /// Google calls this `verification_url`.
+    #[serde(alias="verification_url")]
+    pub verification_uri: Serde<Url>,
+    pub verification_uri_complete: Option<Serde<Url>>,
+    pub expires_in: u64,
+    #[serde(default="default_device_interval")]
+    pub interval: u64,
+}
+
+fn default_device_interval() -> u64 {
+    5
+}
+
+/// Parameters sent from client -> proxy server
+/// to poll for completion of a device authorization grant.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DevicePollParams {
+    #[serde(with="serde_secret_newtype", rename="state")]
+    pub csrf_token: CsrfToken,
+    pub device_code: String,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub provider: Option<String>,
+    /// The scopes sent to `/oauth-cli/device/start`.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub scopes: Option<Vec<String>>,
+}
+
+/// Parameters sent from client -> proxy server
+/// to refresh a session using the handle from an earlier `FinResponse`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RefreshParams {
+    #[serde(with="serde_secret_newtype", rename="state")]
+    pub csrf_token: CsrfToken,
+    pub refresh_handle: String,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct FinResponse<R>
     // where R: Debug + DeserializeOwned + Serialize
 {
 	#[serde(with="serde_secret_newtype", rename="state")]
 	pub csrf_token: CsrfToken,
-	pub response: R,
+    /// The session handler's response, unless it was `sealed`.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+	pub response: Option<R>,
+    /// The response and `refresh_handle` as a `SealedPayload`, sealed
+    /// to the `client_key` sent in `GenParams`.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub sealed: Option<String>,
     pub welcome_redirect: Option<Serde<Url>>,
+    /// Opaque handle for `/oauth-cli/refresh`, if the provider
+    /// issued a refresh token, unless it was `sealed`.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub refresh_handle: Option<String>,
+    /// Lifetime (in seconds) of the underlying access token.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub expires_in: Option<u64>,
+    /// Scopes granted to the underlying access token.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub scopes: Option<Vec<String>>,
+}
+
+/// The contents of `FinResponse::sealed`: everything which
+/// lets its holder act for the `User`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SealedPayload<R> {
+    pub response: R,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub refresh_handle: Option<String>,
+}
+
+impl<R: Serialize> FinResponse<R> {
+    /// Replace the `response` and `refresh_handle` with a payload
+    /// only the holder of the private half of `client_key` can read.
+    pub fn seal(&mut self, client_key: &str) -> Result<(), Error> {
+        if let Some(response) = self.response.take() {
+            let payload = SealedPayload {
+                response,
+                refresh_handle: self.refresh_handle.take(),
+            };
+            let json = serde_json::to_vec(&payload)?;
+            self.sealed = Some(seal::seal(client_key, self.csrf_token.secret(), &json)?);
+        }
+        Ok(())
+    }
+
+    /// Encode the response as a single copy-pasteable string,
+    /// used for `Delivery::Headless`.
+    pub fn to_blob(&self) -> Result<String, Error> {
+        let json = serde_json::to_vec(self)?;
+        Ok(base64::encode_config(&json, base64::URL_SAFE_NO_PAD))
+    }
+}
+
+impl<R: DeserializeOwned> FinResponse<R> {
+    /// Decode a response produced by `to_blob`.
+    pub fn from_blob(blob: &str) -> Result<Self, Error> {
+        let json = base64::decode_config(blob.trim(), base64::URL_SAFE_NO_PAD)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seal::ClientKey;
+
+    #[test]
+    fn google_device_response() {
+        let resp: DeviceStartResponse = serde_json::from_str(r#"{
+            "device_code": "device-code",
+            "user_code": "ABCD-EFGH",
+            "verification_url": "https://www.google.com/device",
+            "expires_in": 1800,
+            "interval": 5
+        }"#).unwrap();
+        assert_eq!(resp.verification_uri.as_str(), "https://www.google.com/device");
+    }
+
+    #[test]
+    fn sealed_response_has_no_secret_in_clear() {
+        let key = ClientKey::generate().unwrap();
+        let state = CsrfToken::new("state".to_string());
+        let mut resp = FinResponse {
+            csrf_token: state.clone(),
+            response: Some("session-secret".to_string()),
+            sealed: None,
+            welcome_redirect: None,
+            refresh_handle: Some("refresh-handle".to_string()),
+            expires_in: Some(3600),
+            scopes: None,
+        };
+        resp.seal(&key.public_key()).unwrap();
+        assert!(resp.response.is_none());
+        assert!(resp.refresh_handle.is_none());
+
+        // As sent to the listener, and shown for headless delivery.
+        let query = serde_qs::to_string(&resp).unwrap();
+        let blob = base64::decode_config(&resp.to_blob().unwrap(), base64::URL_SAFE_NO_PAD).unwrap();
+        for clear in &[query, String::from_utf8(blob).unwrap()] {
+            assert!(!clear.contains("session-secret"), "{}", clear);
+            assert!(!clear.contains("refresh-handle"), "{}", clear);
+        }
+
+        let json = key.open(resp.sealed.as_ref().unwrap(), state.secret()).unwrap();
+        let payload: SealedPayload<String> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(payload.response, "session-secret");
+        assert_eq!(payload.refresh_handle, Some("refresh-handle".to_string()));
+    }
 }
\ No newline at end of file
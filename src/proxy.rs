@@ -12,99 +12,171 @@
 //! These params also include a local port, which the client should be
 //! listening on waiting for the final information from the proxy.
 //!
+//! If the provider issues a refresh token, the proxy keeps it and gives
+//! the client an opaque handle instead. Presenting the handle at
+//! `/oauth-cli/refresh` runs the `SessionHandler` with a fresh token.
+//!
+//! Each provider in `Config::providers` is served under its own name,
+//! e.g. `/oauth-cli/gitlab/start` and `/oauth-cli/gitlab/finish`.
+//!
 
 
 use ::actix::prelude::*;
 // use actix_web::dev::Handler;
 
 use actix_web::{http, server, App, Either, FutureResponse, HttpRequest,
-HttpResponse, Json, Query, Responder, Result, State};
+HttpResponse, Json, Path, Query, Responder, Result, State};
 use actix_web::AsyncResponder;
 use actix_web::middleware::session::RequestSession;
 use actix_web::middleware::Logger;
-use failure::Error;
+use failure::{bail, Error};
 use futures::prelude::*;
 use futures::future;
 use lazy_static::lazy_static;
 use log::*;
-use oauth2::basic::BasicClient;
+use oauth2::basic::{BasicClient, BasicTokenResponse};
 use oauth2::prelude::*;
-use oauth2::{AccessToken, AuthUrl, ClientId, ClientSecret, RedirectUrl,
-    Scope, TokenUrl};
+use oauth2::{AccessToken, AuthUrl, ClientId, ClientSecret, CsrfToken,
+    PkceCodeVerifierS256, RedirectUrl, RefreshToken, ResponseType, Scope, TokenUrl};
 use serde::{Deserialize, de::DeserializeOwned, Serialize};
 use serde_derive::{Deserialize, Serialize};
+use tokio_timer::Timeout;
 use url::Url;
 use url_serde::Serde;
 
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::marker::{PhantomData, Send};
 use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use crate::server::Provider;
+use crate::server::oidc::discover;
+use crate::server::OAuthProvider;
 use crate::msgs::*;
+use crate::seal;
 use crate::util::*;
 
-/// Proxy configuration values.
-#[derive(Clone, Deserialize, Debug)]
-pub struct Config {
-    /// OAuth2 Client ID
-    #[serde(with="serde_newtype")]
-    pub client_id: ClientId,
-
-    /// OAuth2 Client application secret
-    #[serde(with="serde_secret_newtype")]
-    pub client_secret: ClientSecret,
-
-    /// Port on which to run the server
-    pub port: u16,
-
-    /// Authorization provider
-    pub oauth_provider: Provider,
-
-    /// Endpoint for the server callback.
-    /// Should route to this proxy:
-    /// `http://127.0.0.1:{port}/oauth-cli/finish`
-    #[serde(with="url_serde")]
-    pub proxy_url: Url,
-
-    /// Scopes to authorize.
-    #[serde(with="serde_newtype_vec")]
-    pub scopes: Vec<Scope>,
-
-    /// Page to serve when the client finishes 
-    #[serde(with="url_serde")]
-    pub welcome_redirect: Url,
-}
+mod access;
+mod config;
+mod device;
+mod exchange;
+mod mailbox;
+mod pending;
+mod refresh;
+mod token;
+
+pub use self::access::{AccessDenied, AccessPolicy};
+pub use self::config::{Config, ConfigError, ProviderSettings, ScopeNotAllowed};
+use self::exchange::Exchange;
+use self::mailbox::{Collect, Collected, Deposit, Mailbox, Open};
+pub use self::pending::{MemoryPendingLoginStore, PendingLogin, PendingLoginStore, StateError};
+use self::refresh::RefreshHandles;
+pub use self::token::TokenInfo;
+
+/// Number of threads for executing requests to the OAuth 2.0 `Server`.
+const EXECUTOR_THREADS: usize = 4;
+
+/// How long (in seconds) a request to `/oauth-cli/poll` waits for
+/// the response before returning `204 No Content`.
+const LONG_POLL_SECS: u64 = 25;
 
 /// Runs a proxy server which generates a single-use
 /// OAuth2 path for the client, and handles finishing the auth.
 ///
 /// Takes a closure instead of a Handler
-pub fn run_with<F, R>(config: Config, session_handler: F)
-    where F: 'static + Send + Fn(AccessToken) -> Result<R, Error>,
+pub fn run_with<F, R>(config: Config, session_handler: F) -> Result<(), ConfigError>
+    where F: 'static + Send + Fn(TokenInfo) -> Result<R, Error>,
           R: 'static + Debug + DeserializeOwned + Send + Serialize,
 {
     run(config, ClosureHandler(session_handler, PhantomData))
 }
 
+/// Runs a proxy server, handling sessions with a closure
+/// which returns a future.
+///
+/// The future runs on the session handler's event loop, so slow
+/// handlers (e.g. calling out to other services) do not hold up other
+/// logins, provided the closure itself does not block. Handlers which
+/// take longer than `Config::handler_timeout_secs` fail the login.
+pub fn run_with_async<F, Fut, R>(config: Config, session_handler: F) -> Result<(), ConfigError>
+    where F: 'static + Send + Fn(TokenInfo) -> Fut,
+          Fut: 'static + IntoFuture<Item=R, Error=Error>,
+          Fut::Future: 'static,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize,
+{
+    run(config, AsyncClosureHandler(session_handler, PhantomData))
+}
+
+/// As `run_with`, for closures which only need the `AccessToken`.
+///
+/// Closures which do not name the argument type can be passed to
+/// `run_with` directly, since `TokenInfo` dereferences to the token.
+pub fn run_with_access_token<F, R>(config: Config, session_handler: F) -> Result<(), ConfigError>
+    where F: 'static + Send + Fn(AccessToken) -> Result<R, Error>,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize,
+{
+    run_with(config, move |info: TokenInfo| session_handler(info.access_token))
+}
+
 /// Runs a proxy server which generates a single-use
 /// OAuth2 path for the client, and handles finishing the auth.
-pub fn run<H, R>(config: Config, session_handler: H)
+///
+/// Fails if the configuration is invalid, see `Config::validate`,
+/// or the port cannot be bound.
+pub fn run<H, R>(config: Config, session_handler: H) -> Result<(), ConfigError>
+    where H: SessionHandler<R>,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize,
+{
+    let ttl = Duration::from_secs(config.login_ttl_secs);
+    run_with_store(config, session_handler, Arc::new(MemoryPendingLoginStore::new(ttl)))
+}
+
+/// Runs a proxy server, keeping track of logins in progress
+/// in the given `PendingLoginStore`.
+pub fn run_with_store<H, R>(config: Config, session_handler: H, pending: Arc<dyn PendingLoginStore>)
+    -> Result<(), ConfigError>
     where H: SessionHandler<R>,
           R: 'static + Debug + DeserializeOwned + Send + Serialize,
 {
+    config.validate()?;
     let _sys = actix::System::new("olaf2-server");
     let port = config.port;
     let welcome = config.welcome_redirect.clone();
-    let client_addr = OAuthExecutor::from_config(config);
+    let handler_timeout = Duration::from_secs(config.handler_timeout_secs);
+    let access = config.access.clone();
+    let provider_access: HashMap<_, _> = config.providers.iter()
+        .filter_map(|(name, settings)| settings.access.clone().map(|policy| (name.clone(), policy)))
+        .collect();
+    let provider_access = Arc::new(provider_access);
+    let providers: HashMap<_, _> = config.providers.iter()
+        .map(|(name, settings)| -> Result<_, ConfigError> {
+            let executor = OAuthExecutor::from_config(
+                config.clone(), Some(name.clone()), settings.clone(), pending.clone()
+            )?;
+            Ok((name.clone(), executor))
+        })
+        .collect::<Result<_, ConfigError>>()?;
+    let providers = Arc::new(providers);
+    let settings = config.default_provider();
+    let client_addr = OAuthExecutor::from_config(config, None, settings, pending.clone())?;
     let session_handler = Arbiter::start(move |_| session_handler);
+    let mailbox = Arbiter::start(|_| Mailbox::default());
+    let refresh = Arc::new(RefreshHandles::default());
     server::new(move || {
         App::with_state(
             AppState { 
                 oauth_client: client_addr.clone(),
+                providers: providers.clone(),
                 session_handler: session_handler.clone(),
+                mailbox: mailbox.clone(),
+                pending: pending.clone(),
+                refresh: refresh.clone(),
                 marker: PhantomData,
                 welcome_redirect: welcome.clone(),
+                handler_timeout,
+                access: access.clone(),
+                provider_access: provider_access.clone(),
             })
             .middleware(Logger::default())
             .resource("/oauth-cli/start", 
@@ -113,11 +185,31 @@ pub fn run<H, R>(config: Config, session_handler: H)
             .resource("/oauth-cli/finish", 
                 |r| r.method(http::Method::GET)
                      .with(oauth_fin))
+            .resource("/oauth-cli/poll", 
+                |r| r.method(http::Method::POST)
+                     .with(oauth_poll))
+            .resource("/oauth-cli/refresh", 
+                |r| r.method(http::Method::POST)
+                     .with(refresh::oauth_refresh))
+            .resource("/oauth-cli/device/start", 
+                |r| r.method(http::Method::POST)
+                     .with(device::device_start))
+            .resource("/oauth-cli/device/poll", 
+                |r| r.method(http::Method::POST)
+                     .with(device::device_poll))
+            // After the fixed routes, which would otherwise match.
+            .resource("/oauth-cli/{provider}/start", 
+                |r| r.method(http::Method::POST)
+                     .with(oauth_gen_provider))
+            .resource("/oauth-cli/{provider}/finish", 
+                |r| r.method(http::Method::GET)
+                     .with(oauth_fin_provider))
     })
     // .workers(1)
-    .bind(&format!("127.0.0.1:{}", port))
-    .expect(&format!("could not bind to {}", port))
-    .run()
+    .bind(("127.0.0.1", port))
+    .map_err(|cause| ConfigError::Bind { port, cause })?
+    .run();
+    Ok(())
 }
 
 
@@ -125,35 +217,73 @@ impl Handler<GenParams> for OAuthExecutor {
     type Result = Result<Url, Error>;
 
     fn handle(&mut self, msg: GenParams, _: &mut Self::Context) -> Self::Result {
-        use std::mem;
-        // This is unfortunate due to oauth-rs API always taking `self`.
-        if let Some(client) = self.client.take() {
-            mem::replace(&mut self.client, 
-                Some(client.set_redirect_url(
-                    RedirectUrl::new(
-                        Url::parse(
-                            &format!(
-                                "{}oauth-cli/finish?client_port={}",
-                                self.config.proxy_url,
-                                msg.client_port,
-                            )
-                        // TODO: replace with proper URL construction
-                        ).expect("invalid proxy url provided") 
-                    )
-                ))
-            );
-            Ok(self.client.as_ref().unwrap().authorize_url(|| msg.csrf_token).0)
-        } else {
-            panic!("Missing client");
-        }
+        self.authorize_url(msg)
     }
 }
 
-impl Handler<FinParams> for OAuthExecutor {
-    type Result = Result<AccessToken, Error>;
-
-    fn handle(&mut self, msg: FinParams, _: &mut Self::Context) -> Self::Result {
-        Ok(self.client.as_mut().expect("missing client").exchange_code(msg.code)?.access_token().clone())
+impl OAuthExecutor {
+    /// Record a new login, returning the authorization URL for it.
+    fn authorize_url(&self, msg: GenParams) -> Result<Url, Error> {
+        let GenParams { csrf_token, client_port, delivery, scopes, client_key, .. } = msg;
+        if delivery == Delivery::Listener && client_port.is_none() {
+            bail!("missing client_port for listener delivery");
+        }
+        if let Some(ref client_key) = client_key {
+            seal::check_public_key(client_key)?;
+        }
+        let finish_path = match self.name {
+            Some(ref name) => format!("oauth-cli/{}/finish", name),
+            None => "oauth-cli/finish".to_string(),
+        };
+        let redirect_url = RedirectUrl::new(self.config.proxy_url.join(&finish_path)?);
+        // Each login gets its own copy of the client, so concurrent
+        // logins cannot overwrite each other's redirect URL.
+        let client = self.client.clone().set_redirect_url(redirect_url.clone());
+        // The configured scopes are already on the client.
+        let scopes = self.settings.requested_scopes(scopes.as_ref().map(Vec::as_slice))?;
+        let client = scopes[self.settings.scopes.len()..].iter()
+            .fold(client, |client, scope| client.add_scope(scope.clone()));
+        let pkce_verifier = if self.settings.pkce_enabled() {
+            Some(PkceCodeVerifierS256::new_random())
+        } else {
+            None
+        };
+        // Binds the id_token to this login, see OpenID Connect
+        // Core section 3.1.2.1.
+        let nonce = if self.provider.issuer().is_some() {
+            Some(CsrfToken::new_random().secret().to_string())
+        } else {
+            None
+        };
+        let mut params = self.provider.authorize_params();
+        if let Some(ref verifier) = pkce_verifier {
+            params.extend(verifier.authorize_url_params());
+        }
+        if let Some(ref nonce) = nonce {
+            params.push(("nonce", nonce.clone()));
+        }
+        let (url, state) = if params.is_empty() {
+            client.authorize_url(|| csrf_token)
+        } else {
+            client.authorize_url_extension(
+                &ResponseType::new("code".to_string()),
+                || csrf_token,
+                &params,
+            )
+        };
+        let login = PendingLogin {
+            redirect_url,
+            delivery,
+            provider: self.name.clone(),
+            client_port,
+            pkce_verifier,
+            nonce,
+            client_key,
+            scopes,
+            created_at: SystemTime::now(),
+        };
+        self.pending.insert(state.secret(), login)?;
+        Ok(url)
     }
 }
 
@@ -162,93 +292,353 @@ impl Handler<FinParams> for OAuthExecutor {
 /// (This needs to be done on the proxy side, since it uses
 /// the OAuth 2.0 `client_secret`).
 fn oauth_gen<H, R>((params, state): (Json<GenParams>, State<AppState<H, R>>))
-    ->  impl Responder
+    -> FutureResponse<HttpResponse>
+    where H: SessionHandler<R>,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize,
+{
+    start_login(params.into_inner(), &state)
+}
+
+/// As `oauth_gen`, for the provider named in the path.
+fn oauth_gen_provider<H, R>((provider, params, state): (Path<String>, Json<GenParams>, State<AppState<H, R>>))
+    -> FutureResponse<HttpResponse>
+    where H: SessionHandler<R>,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize,
+{
+    let provider = provider.into_inner();
+    let mut params = params.into_inner();
+    if params.provider.as_ref().map_or(false, |p| *p != provider) {
+        return Box::new(future::ok(HttpResponse::BadRequest().finish()));
+    }
+    params.provider = Some(provider);
+    start_login(params, &state)
+}
+
+fn start_login<H, R>(params: GenParams, state: &AppState<H, R>) -> FutureResponse<HttpResponse>
     where H: SessionHandler<R>,
           R: 'static + Debug + DeserializeOwned + Send + Serialize,
 {
     debug!("Received params: {:#?}", params);
-    state.oauth_client
-        .send(params.into_inner())
+    let oauth_client = match state.executor(params.provider.as_ref()) {
+        Some(client) => client,
+        None => return Box::new(future::ok(HttpResponse::NotFound().finish())),
+    };
+    let poll_mailbox = match params.delivery {
+        Delivery::Poll => Some(Open {
+            state: params.csrf_token.secret().to_string(),
+            client_key: params.client_key.clone(),
+        }),
+        _ => None,
+    };
+    let mailbox = state.mailbox.clone();
+    oauth_client
+        .send(params)
         .from_err::<Error>()
-        .and_then(|res| match res {
-            Ok(url) => Ok(HttpResponse::Ok().body(url.to_string())),
-            Err(_) => return Ok(HttpResponse::InternalServerError().into()),
+        .and_then(move |res| match res {
+            // Only opened once the login is recorded, so that
+            // `/start` cannot be used to reset another client's mailbox.
+            Ok(url) => match poll_mailbox {
+                Some(open) => Either::A(mailbox.send(open)
+                    .from_err::<Error>()
+                    .map(move |res| match res {
+                        Ok(()) => HttpResponse::Ok().body(url.to_string()),
+                        Err(e) => {
+                            warn!("Could not open mailbox: {}", e);
+                            HttpResponse::BadRequest().finish()
+                        },
+                    })),
+                None => Either::B(future::ok(HttpResponse::Ok().body(url.to_string()))),
+            },
+            Err(e) => Either::B(future::ok(
+                if let Some(e) = e.downcast_ref::<ScopeNotAllowed>() {
+                    HttpResponse::BadRequest().body(e.to_string())
+                } else if let Some(e) = e.downcast_ref::<StateError>() {
+                    warn!("Rejected login: {}", e);
+                    HttpResponse::BadRequest().finish()
+                } else {
+                    HttpResponse::InternalServerError().into()
+                }
+            )),
         }).responder()
 }
 
 /// Complete the authorization handshake by exchanging the
 /// auth code with a token. Finally creates the client "callback"
 /// by redirecting the client to the server listening on localhost
-fn oauth_fin<H, R>((info, state): (Query<FinParams>, State<AppState<H, R>>)) -> impl Responder
+///
+/// The `state` must match a login started at `/oauth-cli/start`,
+/// which has neither expired nor already been finished.
+fn oauth_fin<H, R>((info, state): (Query<FinParams>, State<AppState<H, R>>)) -> FutureResponse<HttpResponse>
     where H: SessionHandler<R>,
           R: 'static + Debug + DeserializeOwned + Send + Serialize,
 {
-    let port = info.client_port;
-    let nonce = info.csrf_token.clone();
-    state.oauth_client
-    .send(info.into_inner())
-    .from_err()
-    .and_then(move |res: Result<AccessToken, _>| {
-        match res {
-            Ok(token) => {
-                Either::A(state.session_handler.send(Token(token, PhantomData))
-                .map(move |resp: Result<R, _>| {
-                    match resp {
-                        Ok(val) => {
-                            let resp = FinResponse {
-                                csrf_token: nonce,
-                                response: val,
-                                welcome_redirect: Some(Serde(state.welcome_redirect.clone())),
-                            };
-                            let redirect_url = Url::parse(
-                                &format!("http://localhost:{}?{}",
-                                    port,
-                                    serde_qs::to_string(&resp).unwrap(),
-                                )   
-                            ).unwrap();
-
-                            let html = super::get_redirect_page(&redirect_url, &state.welcome_redirect);
-                            HttpResponse::Ok()
-                                .body(html)
-                        },
-                        Err(_) => HttpResponse::InternalServerError().finish(),
-                    }
-                }))
+    finish_login(info.into_inner(), None, state)
+}
+
+/// As `oauth_fin`, for the provider named in the path.
+fn oauth_fin_provider<H, R>((provider, info, state): (Path<String>, Query<FinParams>, State<AppState<H, R>>))
+    -> FutureResponse<HttpResponse>
+    where H: SessionHandler<R>,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize,
+{
+    finish_login(info.into_inner(), Some(provider.into_inner()), state)
+}
+
+fn finish_login<H, R>(info: FinParams, provider: Option<String>, state: State<AppState<H, R>>)
+    -> FutureResponse<HttpResponse>
+    where H: SessionHandler<R>,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize,
+{
+    let FinParams { csrf_token: nonce, code } = info;
+    let login = match state.pending.take(nonce.secret()) {
+        // The code was issued to the client of the provider
+        // the login was started with.
+        Ok(ref login) if login.provider != provider => {
+            warn!("Rejected login: started with provider {:?}, finished with {:?}", login.provider, provider);
+            return Box::new(future::ok(error_page(
+                http::StatusCode::BAD_REQUEST,
+                "Login failed",
+                "Sorry, this login was started with another provider. Please start again from the application.",
+            )));
+        },
+        Ok(login) => login,
+        Err(e) => {
+            warn!("Rejected login: {}", e);
+            return Box::new(future::ok(error_page(
+                http::StatusCode::BAD_REQUEST,
+                "Login failed",
+                &format!("Sorry, {}. Please start again from the application.", e),
+            )));
+        },
+    };
+    let oauth_client = match state.executor(provider.as_ref()) {
+        Some(client) => client.clone(),
+        None => return Box::new(future::ok(error_page(
+            http::StatusCode::NOT_FOUND,
+            "Login failed",
+            "Sorry, this provider is not available. Please start again from the application.",
+        ))),
+    };
+    let port = login.client_port;
+    let delivery = login.delivery;
+    let client_key = login.client_key.clone();
+    oauth_client
+    .send(Exchange { code, login })
+    .from_err::<Error>()
+    .and_then(move |res: Result<TokenInfo, _>| {
+        let token = match res {
+            Ok(token) => token,
+            Err(e) => {
+                warn!("Token exchange failed: {}", e);
+                return Either::B(future::ok(error_page(
+                    http::StatusCode::BAD_GATEWAY,
+                    "Login failed",
+                    "Sorry, the login could not be completed with the provider. Please try again.",
+                )));
             },
-            Err(_) => Either::B(future::ok(HttpResponse::InternalServerError().finish())),
+        };
+        if let Err(e) = state.check_access(provider.as_ref(), &token) {
+            warn!("Denied login: {}", e);
+            return Either::B(future::ok(error_page(
+                http::StatusCode::FORBIDDEN,
+                "Access denied",
+                &format!("Sorry, {}.", e),
+            )));
         }
+        // The refresh handle is a bearer credential, so it only
+        // passes through the browser (or mailbox) if it can be sealed.
+        let refresh_token = match client_key {
+            Some(_) => token.refresh_token.clone(),
+            None => None,
+        };
+        Either::A(new_session(&state, token, nonce, provider, refresh_token)
+        .map(move |resp| match resp {
+            Some(mut resp) => {
+                resp.welcome_redirect = Some(Serde(state.welcome_redirect.clone()));
+                // The response passes through the browser, so only
+                // the client which started the login should read it.
+                match client_key.map_or(Ok(()), |key| resp.seal(&key)) {
+                    Ok(()) => deliver(resp, delivery, port, &state),
+                    Err(e) => {
+                        warn!("Could not seal response: {}", e);
+                        error_page(
+                            http::StatusCode::INTERNAL_SERVER_ERROR,
+                            "Login failed",
+                            "Sorry, something went wrong finishing the login. Please try again.",
+                        )
+                    },
+                }
+            },
+            None => error_page(
+                http::StatusCode::INTERNAL_SERVER_ERROR,
+                "Login failed",
+                "Sorry, something went wrong finishing the login. Please try again.",
+            ),
+        }))
     }).responder()
 }
 
+/// Run the `SessionHandler` for a new `token`, and build the
+/// `FinResponse` for the session, keeping any `refresh_token` at the
+/// proxy behind an opaque handle.
+///
+/// Resolves to `None` if the handler failed, which is logged.
+fn new_session<H, R>(
+    state: &AppState<H, R>,
+    token: TokenInfo,
+    csrf_token: CsrfToken,
+    provider: Option<String>,
+    refresh_token: Option<RefreshToken>,
+) -> impl Future<Item=Option<FinResponse<R>>, Error=Error>
+    where H: SessionHandler<R>,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize,
+{
+    let refresh = state.refresh.clone();
+    let expires_in = token.expires_in;
+    let scopes = token.granted_scopes().to_vec();
+    state.session_handler.send(Token(token, PhantomData))
+        .timeout(state.handler_timeout)
+        .then(move |resp| -> Result<Option<FinResponse<R>>, Error> {
+            Ok(match resp {
+                Ok(Ok(response)) => Some(FinResponse {
+                    csrf_token,
+                    response: Some(response),
+                    sealed: None,
+                    welcome_redirect: None,
+                    refresh_handle: refresh_token.map(|token| refresh.issue(token, provider, scopes.clone())),
+                    expires_in: expires_in.map(|d| d.as_secs()),
+                    scopes: Some(scopes.iter().map(|s| s.to_string()).collect()),
+                }),
+                Ok(Err(e)) => {
+                    warn!("Session handler failed: {}", e);
+                    None
+                },
+                Err(e) => {
+                    warn!("Session handler did not respond: {}", e);
+                    None
+                },
+            })
+        })
+}
+
+/// Render a friendly error page for the `User`.
+fn error_page(status: http::StatusCode, title: &str, message: &str) -> HttpResponse {
+    HttpResponse::build(status)
+        .content_type("text/html; charset=utf-8")
+        .body(super::get_error_page(title, message))
+}
+
+/// Send the final response to the client, as requested by `delivery`.
+fn deliver<H, R>(resp: FinResponse<R>, delivery: Delivery, port: Option<u16>, state: &AppState<H, R>)
+    -> HttpResponse
+    where H: SessionHandler<R>,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize,
+{
+    match (delivery, port) {
+        (Delivery::Headless, _) => match resp.to_blob() {
+            Ok(blob) => HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(super::get_headless_page(&blob, &state.welcome_redirect)),
+            Err(_) => HttpResponse::InternalServerError().finish(),
+        },
+        (Delivery::Poll, _) => match serde_json::to_string(&resp) {
+            Ok(body) => {
+                state.mailbox.do_send(Deposit {
+                    state: resp.csrf_token.secret().to_string(),
+                    body,
+                });
+                HttpResponse::Found()
+                    .header(http::header::LOCATION, state.welcome_redirect.to_string())
+                    .finish()
+            },
+            Err(_) => HttpResponse::InternalServerError().finish(),
+        },
+        (Delivery::Listener, Some(port)) => {
+            let redirect_url = Url::parse(
+                &format!("http://localhost:{}?{}",
+                    port,
+                    serde_qs::to_string(&resp).unwrap(),
+                )   
+            ).unwrap();
+
+            let html = super::get_redirect_page(&redirect_url, &state.welcome_redirect);
+            HttpResponse::Ok()
+                .body(html)
+        },
+        (Delivery::Listener, None) => HttpResponse::BadRequest().finish(),
+    }
+}
+
+/// Long-poll for the response of a login using `Delivery::Poll`.
+///
+/// Responds with the `FinResponse` as JSON when available,
+/// `204 No Content` if the client should poll again, or
+/// `410 Gone` if the `state` is unknown or has expired, or
+/// the `client_key` is not the one the login was started with.
+fn oauth_poll<H, R>((params, state): (Json<PollParams>, State<AppState<H, R>>)) -> impl Responder
+    where H: SessionHandler<R>,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize,
+{
+    let PollParams { csrf_token, client_key } = params.into_inner();
+    state.mailbox
+        .send(Collect { state: csrf_token.secret().to_string(), client_key })
+        .from_err::<Error>()
+        .and_then(|res| match res {
+            Ok(Collected::Ready(body)) => Either::A(future::ok(json_response(body))),
+            Ok(Collected::Pending(rx)) => Either::B(
+                Timeout::new(rx, Duration::from_secs(LONG_POLL_SECS))
+                    .then(|res| match res {
+                        Ok(body) => Ok(json_response(body)),
+                        Err(_) => Ok(HttpResponse::NoContent().finish()),
+                    })
+            ),
+            Err(_) => Either::A(future::ok(HttpResponse::Gone().finish())),
+        }).responder()
+}
+
+fn json_response(body: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body)
+}
+
 ///// Annoying stuff
 
-#[derive(Clone, Debug)]
+/// Executes the OAuth 2.0 requests to the `Server`.
+///
+/// Runs on a pool of threads, since the token exchange blocks.
+/// The only shared state is the record of pending logins.
+#[derive(Clone)]
 struct OAuthExecutor {
-    client: Option<BasicClient>,
+    client: BasicClient,
     config: Config,
+    /// Name of the provider in `Config::providers`,
+    /// or `None` for the default provider.
+    name: Option<String>,
+    settings: ProviderSettings,
+    provider: Arc<dyn OAuthProvider>,
+    token_url: TokenUrl,
+    http: reqwest::Client,
+    pending: Arc<dyn PendingLoginStore>,
 }
 
 impl Actor for OAuthExecutor {
-    type Context = Context<Self>;
+    type Context = SyncContext<Self>;
 }
 
 impl Message for GenParams {
     type Result = Result<Url, Error>;
 }
 
-impl Message for FinParams {
-    type Result = Result<AccessToken, Error>;
-}
-
-/// Type to allow generic handling of the `AccessToken`
+/// Type to allow generic handling of the `TokenInfo`
 /// into any suitable type `R`.
-pub struct Token<R>(pub AccessToken, pub(crate) PhantomData<R>);
+pub struct Token<R>(pub TokenInfo, pub(crate) PhantomData<R>);
 
 impl<R: 'static + Debug + DeserializeOwned + Send> Message for Token<R> {
     type Result = Result<R, Error>;
 }
 
-/// Trait to encapsulate handle `AccessToken`s output after completing
+/// Trait to encapsulate handle `TokenInfo`s output after completing
 /// the auth process.
 pub trait SessionHandler<R>: Handler<Token<R>> + Actor<Context=Context<Self>> + Send
     where R: 'static + Debug + DeserializeOwned + Send + Serialize
@@ -262,60 +652,146 @@ struct AppState<H, R>
 {
     // pub config: Config,
     pub oauth_client: Addr<OAuthExecutor>,
+    pub providers: Arc<HashMap<String, Addr<OAuthExecutor>>>,
     pub session_handler: Addr<H>,
+    pub mailbox: Addr<Mailbox>,
+    pub pending: Arc<dyn PendingLoginStore>,
+    pub refresh: Arc<RefreshHandles>,
     pub marker: PhantomData<R>,
     pub welcome_redirect: Url,
+    pub handler_timeout: Duration,
+    pub access: Option<AccessPolicy>,
+    pub provider_access: Arc<HashMap<String, AccessPolicy>>,
+}
+
+impl<H, R> AppState<H, R>
+    where H: SessionHandler<R>,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize,
+{
+    /// The executor for the named `provider`, or for
+    /// the default provider if `None`.
+    fn executor(&self, provider: Option<&String>) -> Option<&Addr<OAuthExecutor>> {
+        match provider {
+            Some(name) => self.providers.get(name),
+            None => Some(&self.oauth_client),
+        }
+    }
+
+    /// Check the `User` behind `token` against the `AccessPolicy`
+    /// of the named `provider` (or the default provider), if any.
+    fn check_access(&self, provider: Option<&String>, token: &TokenInfo) -> Result<(), AccessDenied> {
+        let policy = match provider {
+            Some(name) => self.provider_access.get(name),
+            None => self.access.as_ref(),
+        };
+        match policy {
+            Some(policy) => policy.check(token.identity.as_ref()),
+            None => Ok(()),
+        }
+    }
 }
 
 impl OAuthExecutor {
-    fn from_config(config: Config) -> Addr<Self> {
-        let Config {
+    /// Collect the `TokenInfo` for a new `token`, including
+    /// the `User`'s identity.
+    fn token_info(
+        &self,
+        token: BasicTokenResponse,
+        requested_scopes: Vec<Scope>,
+        client_port: Option<u16>,
+        requested_at: SystemTime,
+    ) -> Result<TokenInfo, Error> {
+        let mut info = TokenInfo::new(
+            token, &*self.provider, self.name.clone(), requested_scopes, client_port, requested_at
+        );
+        info.identity = self.provider.identity(&self.http, &info.access_token)?;
+        Ok(info)
+    }
+
+    fn from_config(
+        config: Config,
+        name: Option<String>,
+        settings: ProviderSettings,
+        pending: Arc<dyn PendingLoginStore>,
+    ) -> Result<Addr<Self>, ConfigError> {
+        let client = Self::new(config, name, settings, pending)?;
+        Ok(SyncArbiter::start(EXECUTOR_THREADS, move || client.clone()))
+    }
+
+    fn new(
+        config: Config,
+        name: Option<String>,
+        mut settings: ProviderSettings,
+        pending: Arc<dyn PendingLoginStore>,
+    ) -> Result<Self, ConfigError> {
+        let provider = settings.oauth_provider.resolve()
+            .map_err(|e| ConfigError::Invalid(e.to_string()))?;
+        // The endpoints of an OpenID Connect provider are discovered,
+        // so check that works before starting.
+        if let Some(issuer) = provider.issuer() {
+            discover(&issuer).map_err(|e| ConfigError::Invalid(format!(
+                "could not discover OpenID configuration for {}: {}", issuer, e
+            )))?;
+        }
+        if settings.scopes.is_empty() {
+            settings.scopes = provider.default_scopes();
+        }
+        if provider.issuer().is_some() && !settings.scopes.iter().any(|s| s.as_str() == "openid") {
+            settings.scopes.insert(0, Scope::new("openid".to_string()));
+        }
+        let ProviderSettings {
             client_id,
             client_secret,
-            port,
-            proxy_url,
-            oauth_provider,
             scopes,
-            welcome_redirect,
-        } = config.clone();
+            ..
+        } = settings.clone();
 
-        let (auth_url, token_url) = oauth_provider.into_urls();
+        // Only used for the authorization URL; token requests are
+        // made directly, see `exchange`.
+        let (auth_url, token_url) = provider.urls();
         let mut client = BasicClient::new(
             client_id,
             Some(client_secret),
             auth_url,
-            Some(token_url),
+            Some(token_url.clone()),
         );
         for scope in scopes {
             client = client.add_scope(scope);
         }
-        let client = Self { client: Some(client), config: config };
-
-        Arbiter::start(move |_| client.clone())
+        Ok(Self {
+            client,
+            config,
+            name,
+            settings,
+            provider,
+            token_url,
+            http: reqwest::Client::new(),
+            pending,
+        })
     }
 }
 
 impl<R> Deref for Token<R> {
-    type Target = AccessToken;
-    fn deref(&self) -> &AccessToken {
+    type Target = TokenInfo;
+    fn deref(&self) -> &TokenInfo {
         &self.0
     }
 }
 
 
 struct ClosureHandler<
-    F: Fn(AccessToken) -> Result<R, Error>,
+    F: Fn(TokenInfo) -> Result<R, Error>,
     R: 'static + Debug + DeserializeOwned + Send + Sized + Serialize>(F, PhantomData<R>);
 
 impl<F, R> Actor for ClosureHandler<F, R>
-    where F: 'static + Fn(AccessToken) -> Result<R, Error>,
+    where F: 'static + Fn(TokenInfo) -> Result<R, Error>,
           R: 'static + Debug + DeserializeOwned + Send + Serialize + Sized
 {
     type Context = Context<Self>;
 }
 
 impl<F, R> Handler<Token<R>> for ClosureHandler<F, R>
-    where F: 'static + Fn(AccessToken) -> Result<R, Error>,
+    where F: 'static + Fn(TokenInfo) -> Result<R, Error>,
           R: 'static + Debug + DeserializeOwned + Send + Serialize + Sized
 {
     type Result = Result<R, Error>;
@@ -326,6 +802,138 @@ impl<F, R> Handler<Token<R>> for ClosureHandler<F, R>
 }
 
 impl<F, R> SessionHandler<R> for ClosureHandler<F, R>
-    where F: 'static + Fn(AccessToken) -> Result<R, Error> + Send,
+    where F: 'static + Fn(TokenInfo) -> Result<R, Error> + Send,
           R: 'static + Debug + DeserializeOwned + Send + Serialize + Sized  
- {}
\ No newline at end of file
+ {}
+
+/// As `ClosureHandler`, for closures returning a future.
+///
+/// `Fut` is only carried in the type to tie it to `F`.
+struct AsyncClosureHandler<F, Fut, R>(F, PhantomData<fn() -> (Fut, R)>);
+
+impl<F, Fut, R> Actor for AsyncClosureHandler<F, Fut, R>
+    where F: 'static + Fn(TokenInfo) -> Fut,
+          Fut: 'static + IntoFuture<Item=R, Error=Error>,
+          Fut::Future: 'static,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize
+{
+    type Context = Context<Self>;
+}
+
+impl<F, Fut, R> Handler<Token<R>> for AsyncClosureHandler<F, Fut, R>
+    where F: 'static + Fn(TokenInfo) -> Fut,
+          Fut: 'static + IntoFuture<Item=R, Error=Error>,
+          Fut::Future: 'static,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize
+{
+    type Result = ResponseFuture<R, Error>;
+
+    fn handle(&mut self, msg: Token<R>, _: &mut Self::Context) -> Self::Result {
+        Box::new((self.0)(msg.0).into_future())
+    }
+}
+
+impl<F, Fut, R> SessionHandler<R> for AsyncClosureHandler<F, Fut, R>
+    where F: 'static + Fn(TokenInfo) -> Fut + Send,
+          Fut: 'static + IntoFuture<Item=R, Error=Error>,
+          Fut::Future: 'static,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize
+{}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::MockServer;
+    use oauth2::{AuthorizationCode, CsrfToken};
+    use std::collections::HashMap;
+
+    fn executor(server: &MockServer, name: Option<&str>) -> OAuthExecutor {
+        let config: Config = toml::from_str(&format!(r#"
+            client_id = "client"
+            client_secret = "secret"
+            port = 8081
+            proxy_url = "http://localhost:8081/"
+            welcome_redirect = "http://localhost:8080/"
+            pkce = true
+
+            [oauth_provider.Custom]
+            auth_url = "{}"
+            token_url = "{}"
+        "#, server.url("authorize"), server.url("token"))).unwrap();
+        let settings = config.default_provider();
+        let pending = Arc::new(MemoryPendingLoginStore::new(Duration::from_secs(600)));
+        OAuthExecutor::new(config, name.map(str::to_string), settings, pending).unwrap()
+    }
+
+    /// Start a login with `state`, returning the
+    /// query of its authorization URL.
+    fn start(executor: &OAuthExecutor, state: &str, port: u16) -> HashMap<String, String> {
+        let url = executor.authorize_url(GenParams {
+            csrf_token: CsrfToken::new(state.to_string()),
+            client_port: Some(port),
+            delivery: Delivery::Listener,
+            provider: None,
+            scopes: None,
+            client_key: None,
+        }).unwrap();
+        url.query_pairs().into_owned().collect()
+    }
+
+    #[test]
+    fn interleaved_logins_keep_their_own_client_port() {
+        let server = MockServer::start(&[
+            ("/token", r#"{"access_token": "token", "token_type": "bearer"}"#),
+        ]);
+        let executor = executor(&server, None);
+        let a = start(&executor, "state-a", 1111);
+        let b = start(&executor, "state-b", 2222);
+        // The redirect URI no longer carries the client_port, so
+        // it is the same for every login.
+        assert_eq!(a["redirect_uri"], b["redirect_uri"]);
+
+        // Finish in the opposite order to starting. Each login's
+        // client_port comes back from its own pending record.
+        let mut verifiers = vec![];
+        for &(state, code, port) in &[("state-b", "code-b", 2222), ("state-a", "code-a", 1111)] {
+            let login = executor.pending.take(state).unwrap();
+            assert_eq!(login.client_port, Some(port));
+            verifiers.push(login.pkce_verifier.as_ref().unwrap().secret().to_string());
+            executor.exchange(Exchange {
+                code: AuthorizationCode::new(code.to_string()),
+                login,
+            }).unwrap();
+        }
+        assert_ne!(verifiers[0], verifiers[1]);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        for (request, authorize, code, verifier) in vec![
+            (&requests[0], &b, "code-b", &verifiers[0]),
+            (&requests[1], &a, "code-a", &verifiers[1]),
+        ] {
+            let form = request.form();
+            assert_eq!(request.path, "/token");
+            assert_eq!(form["code"], code);
+            assert_eq!(form["redirect_uri"], authorize["redirect_uri"]);
+            // PKCE: each exchange sends its own login's verifier.
+            assert_eq!(&form["code_verifier"], verifier);
+        }
+    }
+
+    #[test]
+    fn token_names_the_configured_provider() {
+        let server = MockServer::start(&[
+            ("/token", r#"{"access_token": "token", "token_type": "bearer"}"#),
+        ]);
+        for &name in &[None, Some("corp")] {
+            let executor = executor(&server, name);
+            start(&executor, "state", 1111);
+            let login = executor.pending.take("state").unwrap();
+            let info = executor.exchange(Exchange {
+                code: AuthorizationCode::new("code".to_string()),
+                login,
+            }).unwrap();
+            assert_eq!(info.provider, name.map(str::to_string));
+        }
+    }
+}
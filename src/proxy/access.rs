@@ -0,0 +1,171 @@
+//! Declarative restrictions on who may log in.
+//!
+//! The policy is checked once the `User`'s `Identity` is known,
+//! before the `SessionHandler` runs.
+
+use failure::Fail;
+use serde_derive::Deserialize;
+
+use crate::server::Identity;
+
+/// Who may log in through the proxy.
+///
+/// A `User` is allowed if they match any of the lists. For example:
+///
+/// ```toml
+/// [access]
+/// allowed_orgs = ["my-org"]
+/// allowed_logins = ["outside-collaborator"]
+/// ```
+///
+/// Comparisons ignore case. Organization and team memberships
+/// usually need extra scopes, e.g. `read:org` for Github.
+///
+/// An `[access]` section must list someone: one with every list empty
+/// would turn everyone away, so is rejected when the config is loaded.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AccessPolicy {
+    #[serde(default)]
+    pub allowed_logins: Vec<String>,
+
+    /// Domains of verified email addresses, e.g. `example.com`.
+    /// Not available with Azure AD, see `server::Provider::AzureAd`.
+    #[serde(default)]
+    pub allowed_email_domains: Vec<String>,
+
+    #[serde(default)]
+    pub allowed_orgs: Vec<String>,
+
+    /// Teams, as `org/team-slug`.
+    #[serde(default)]
+    pub allowed_teams: Vec<String>,
+}
+
+/// Reasons a `User` is turned away by the `AccessPolicy`.
+#[derive(Debug, Fail)]
+pub enum AccessDenied {
+    #[fail(display = "the provider did not tell us who you are")]
+    Unidentified,
+
+    #[fail(display = "{} is not allowed to use this application", _0)]
+    NotAllowed(String),
+}
+
+impl AccessPolicy {
+    /// Check whether the `User` with `identity` may log in.
+    pub fn check(&self, identity: Option<&Identity>) -> Result<(), AccessDenied> {
+        let identity = identity.ok_or(AccessDenied::Unidentified)?;
+        let contains = |list: &[String], value: &str| list.iter().any(|v| v.eq_ignore_ascii_case(value));
+
+        let login_ok = identity.login.as_ref()
+            .map_or(false, |login| contains(&self.allowed_logins, login));
+        let email_ok = identity.email.as_ref()
+            .and_then(|email| email.rfind('@').map(|at| &email[at + 1..]))
+            .map_or(false, |domain| contains(&self.allowed_email_domains, domain));
+        let group_ok = identity.groups.iter()
+            .any(|g| contains(&self.allowed_orgs, g) || contains(&self.allowed_teams, g));
+
+        if login_ok || email_ok || group_ok {
+            Ok(())
+        } else {
+            let who = identity.login.clone()
+                .or_else(|| identity.email.clone())
+                .unwrap_or_else(|| identity.id.clone());
+            Err(AccessDenied::NotAllowed(who))
+        }
+    }
+
+    /// Whether no one at all is allowed.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.allowed_logins.is_empty()
+            && self.allowed_email_domains.is_empty()
+            && self.allowed_orgs.is_empty()
+            && self.allowed_teams.is_empty()
+    }
+
+    /// Teams which are not written as `org/team-slug`.
+    pub(crate) fn malformed_teams(&self) -> impl Iterator<Item=&String> {
+        self.allowed_teams.iter().filter(|team| {
+            let mut parts = team.splitn(2, '/');
+            match (parts.next(), parts.next()) {
+                (Some(org), Some(slug)) => org.is_empty() || slug.is_empty() || slug.contains('/'),
+                _ => true,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    fn policy() -> AccessPolicy {
+        AccessPolicy {
+            allowed_logins: strings(&["Outside-Collaborator"]),
+            allowed_email_domains: strings(&["example.com"]),
+            allowed_orgs: strings(&["my-org"]),
+            allowed_teams: strings(&["other-org/admins"]),
+        }
+    }
+
+    fn identity(login: Option<&str>, email: Option<&str>, groups: &[&str]) -> Identity {
+        Identity {
+            id: "12345".to_string(),
+            login: login.map(str::to_string),
+            email: email.map(str::to_string),
+            name: None,
+            groups: strings(groups),
+        }
+    }
+
+    fn denied(identity: &Identity) -> String {
+        match policy().check(Some(identity)) {
+            Err(AccessDenied::NotAllowed(who)) => who,
+            other => panic!("unexpected result {:?} for {:?}", other, identity),
+        }
+    }
+
+    #[test]
+    fn allowed_by_login() {
+        policy().check(Some(&identity(Some("outside-collaborator"), None, &[]))).unwrap();
+        assert_eq!(denied(&identity(Some("someone-else"), None, &[])), "someone-else");
+    }
+
+    #[test]
+    fn allowed_by_email_domain() {
+        policy().check(Some(&identity(None, Some("user@EXAMPLE.com"), &[]))).unwrap();
+        assert_eq!(denied(&identity(None, Some("user@example.com.evil.test"), &[])), "user@example.com.evil.test");
+        assert_eq!(denied(&identity(None, Some("example.com@evil.test"), &[])), "example.com@evil.test");
+    }
+
+    #[test]
+    fn allowed_by_org() {
+        policy().check(Some(&identity(Some("member"), None, &["My-Org"]))).unwrap();
+        assert_eq!(denied(&identity(Some("member"), None, &["my-org-fork", "other-org"])), "member");
+    }
+
+    #[test]
+    fn allowed_by_team() {
+        policy().check(Some(&identity(Some("admin"), None, &["other-org", "Other-Org/Admins"]))).unwrap();
+        assert_eq!(denied(&identity(Some("member"), None, &["other-org", "other-org/users"])), "member");
+        // A team does not stand in for its organization.
+        assert_eq!(denied(&identity(Some("admin"), None, &["my-org/admins"])), "admin");
+    }
+
+    #[test]
+    fn denied_names_the_user() {
+        assert_eq!(denied(&identity(None, None, &[])), "12345");
+    }
+
+    #[test]
+    fn unidentified_is_denied() {
+        match policy().check(None) {
+            Err(AccessDenied::Unidentified) => {},
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,645 @@
+//! Loading and validation of the proxy configuration.
+//!
+//! Configuration is layered, with later sources taking precedence:
+//!
+//!  1. A TOML file.
+//!  2. Environment variables named `OLAF2_<KEY>`, e.g.
+//!     `OLAF2_CLIENT_SECRET`. List values such as `scopes`
+//!     are comma-separated. Providers under `providers` (see below)
+//!     are set with `OLAF2_PROVIDERS_<NAME>_<KEY>`, e.g.
+//!     `OLAF2_PROVIDERS_GITLAB_CLIENT_SECRET`, with any `-` in the
+//!     name written as `_`. The provider must be in the file.
+//!  3. Explicit overrides, e.g. from command-line flags.
+//!
+//! The provider given at the top level is served at `/oauth-cli/start`.
+//! Further providers, each with their own client, can be served from the
+//! same proxy at `/oauth-cli/{name}/start`:
+//!
+//! ```toml
+//! [providers.gitlab]
+//! client_id = "..."
+//! client_secret = "..."
+//! scopes = ["read_user"]
+//! allowed_scopes = ["api"]
+//! device_flow = true
+//!
+//! [providers.gitlab.oauth_provider.GitLab]
+//! base_url = "https://gitlab.example.com/"
+//!
+//! # Each provider has its own policy, if any.
+//! [providers.gitlab.access]
+//! allowed_orgs = ["my-group"]
+//! ```
+
+use failure::Fail;
+use log::*;
+use oauth2::prelude::*;
+use oauth2::{ClientId, ClientSecret, Scope};
+use serde_derive::Deserialize;
+use toml::value::{Table, Value};
+use url::{Host, Url};
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::access::AccessPolicy;
+use crate::server::{Provider, ProviderConfig};
+use crate::util::*;
+
+/// Prefix for environment variables overriding configuration values.
+const ENV_PREFIX: &str = "OLAF2_";
+
+/// Proxy configuration values.
+#[derive(Clone, Deserialize, Debug)]
+pub struct Config {
+    /// OAuth2 Client ID
+    #[serde(with="serde_newtype")]
+    pub client_id: ClientId,
+
+    /// OAuth2 Client application secret
+    #[serde(with="serde_secret_newtype")]
+    pub client_secret: ClientSecret,
+
+    /// Port on which to run the server
+    pub port: u16,
+
+    /// Authorization provider
+    pub oauth_provider: ProviderConfig,
+
+    /// Endpoint for the server callback.
+    /// Should route to this proxy:
+    /// `http://127.0.0.1:{port}/oauth-cli/finish`
+    #[serde(with="url_serde")]
+    pub proxy_url: Url,
+
+    /// Scopes to authorize.
+    /// Defaults to the provider's `default_scopes`.
+    #[serde(default, with="serde_newtype_vec")]
+    pub scopes: Vec<Scope>,
+
+    /// Further scopes clients may ask for, see `GenParams::scopes`.
+    #[serde(default, with="serde_newtype_vec")]
+    pub allowed_scopes: Vec<Scope>,
+
+    /// Page to serve when the client finishes 
+    #[serde(with="url_serde")]
+    pub welcome_redirect: Url,
+
+    /// Allow clients to use the device authorization grant
+    /// (RFC 8628), if the provider supports it.
+    #[serde(default)]
+    pub device_flow: bool,
+
+    /// Use PKCE (RFC 7636) for the authorization code exchange.
+    /// Defaults to on for providers which support it.
+    #[serde(default)]
+    pub pkce: Option<bool>,
+
+    /// Time (in seconds) the `User` has to complete a login
+    /// after it was started.
+    #[serde(default="default_login_ttl")]
+    pub login_ttl_secs: u64,
+
+    /// Time (in seconds) the `SessionHandler` has to produce
+    /// a response before the login fails.
+    #[serde(default="default_handler_timeout")]
+    pub handler_timeout_secs: u64,
+
+    /// Who may log in with the provider given at the top level.
+    /// Everyone, if not set.
+    #[serde(default)]
+    pub access: Option<AccessPolicy>,
+
+    /// Further providers to serve, by name.
+    /// These do not share the top level `access` policy.
+    #[serde(default)]
+    pub providers: BTreeMap<String, ProviderSettings>,
+}
+
+/// Settings for one provider served by the proxy.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ProviderSettings {
+    /// OAuth2 Client ID
+    #[serde(with="serde_newtype")]
+    pub client_id: ClientId,
+
+    /// OAuth2 Client application secret
+    #[serde(with="serde_secret_newtype")]
+    pub client_secret: ClientSecret,
+
+    /// Authorization provider
+    pub oauth_provider: ProviderConfig,
+
+    /// Scopes to authorize.
+    /// Defaults to the provider's `default_scopes`.
+    #[serde(default, with="serde_newtype_vec")]
+    pub scopes: Vec<Scope>,
+
+    /// Further scopes clients may ask for, see `GenParams::scopes`.
+    #[serde(default, with="serde_newtype_vec")]
+    pub allowed_scopes: Vec<Scope>,
+
+    /// Allow clients to use the device authorization grant
+    /// (RFC 8628), if the provider supports it.
+    #[serde(default)]
+    pub device_flow: bool,
+
+    /// Use PKCE (RFC 7636) for the authorization code exchange.
+    /// Defaults to on for providers which support it.
+    #[serde(default)]
+    pub pkce: Option<bool>,
+
+    /// Who may log in with this provider. Everyone, if not set.
+    #[serde(default)]
+    pub access: Option<AccessPolicy>,
+}
+
+fn default_login_ttl() -> u64 {
+    600
+}
+
+fn default_handler_timeout() -> u64 {
+    30
+}
+
+impl Config {
+    /// Whether to use PKCE for the authorization code exchange.
+    pub fn pkce_enabled(&self) -> bool {
+        self.default_provider().pkce_enabled()
+    }
+
+    /// Settings for the provider given at the top level.
+    pub fn default_provider(&self) -> ProviderSettings {
+        ProviderSettings {
+            client_id: self.client_id.clone(),
+            client_secret: self.client_secret.clone(),
+            oauth_provider: self.oauth_provider.clone(),
+            scopes: self.scopes.clone(),
+            allowed_scopes: self.allowed_scopes.clone(),
+            device_flow: self.device_flow,
+            pkce: self.pkce,
+            access: self.access.clone(),
+        }
+    }
+
+    /// Load the configuration from the TOML file at `path` (if any),
+    /// then `OLAF2_*` environment variables, then `overrides`,
+    /// given as `(key, value)` pairs.
+    ///
+    /// The result is validated before being returned.
+    pub fn load<P: AsRef<Path>>(path: Option<P>, overrides: &[(String, String)])
+        -> Result<Config, ConfigError>
+    {
+        let mut table = match path {
+            Some(path) => {
+                let path = path.as_ref();
+                let contents = fs::read_to_string(path).map_err(|e| ConfigError::Read {
+                    path: path.display().to_string(),
+                    cause: e,
+                })?;
+                match contents.parse::<Value>().map_err(ConfigError::Parse)? {
+                    Value::Table(table) => table,
+                    _ => unreachable!("TOML documents are tables"),
+                }
+            },
+            None => Default::default(),
+        };
+
+        for (key, value) in env::vars() {
+            if key.starts_with(ENV_PREFIX) {
+                let key = key[ENV_PREFIX.len()..].to_lowercase();
+                if is_known_key(&key) {
+                    table.insert(key.clone(), parse_value(&key, &value)?);
+                } else if !set_provider_value(&mut table, &key, &value)? {
+                    debug!("Ignoring unknown environment variable {}{}", ENV_PREFIX, key.to_uppercase());
+                }
+            }
+        }
+
+        for (key, value) in overrides {
+            let key = key.replace('-', "_");
+            if !is_known_key(&key) {
+                return Err(ConfigError::UnknownKey(key));
+            }
+            table.insert(key.clone(), parse_value(&key, value)?);
+        }
+
+        let config: Config = Value::Table(table).try_into().map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check the configuration values are consistent.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.default_provider().validate()?;
+        for (name, provider) in &self.providers {
+            // Names become a path segment, which must not clash
+            // with the device flow routes.
+            let valid = !name.is_empty() && name != "device"
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid {
+                return Err(ConfigError::Invalid(format!("invalid provider name {:?}", name)));
+            }
+            provider.validate().map_err(|e| match e {
+                ConfigError::Invalid(msg) => ConfigError::Invalid(format!("providers.{}: {}", name, msg)),
+                e => e,
+            })?;
+        }
+        if self.handler_timeout_secs == 0 {
+            return Err(ConfigError::Invalid("handler_timeout_secs must be positive".to_string()));
+        }
+        if !self.proxy_url.path().ends_with('/') {
+            return Err(ConfigError::Invalid(format!(
+                "proxy_url must end with a '/', e.g. \"{}/\"", self.proxy_url
+            )));
+        }
+        // When running locally, the proxy_url must point at this server.
+        // Otherwise, it may sit behind a reverse proxy on another port.
+        let local = match self.proxy_url.host() {
+            Some(Host::Domain(domain)) => domain == "localhost",
+            Some(Host::Ipv4(ip)) => ip.is_loopback(),
+            Some(Host::Ipv6(ip)) => ip.is_loopback(),
+            None => false,
+        };
+        if local && self.proxy_url.port_or_known_default() != Some(self.port) {
+            return Err(ConfigError::Invalid(format!(
+                "port {} disagrees with proxy_url {}", self.port, self.proxy_url
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl ProviderSettings {
+    /// Whether to use PKCE for the authorization code exchange.
+    pub fn pkce_enabled(&self) -> bool {
+        self.pkce.unwrap_or_else(|| {
+            self.oauth_provider.resolve().map_or(true, |provider| provider.supports_pkce())
+        })
+    }
+
+    /// The scopes to request for a login, given the `extra` scopes
+    /// the client asked for.
+    ///
+    /// The configured `scopes` are always requested. Each extra scope
+    /// must be one of them, or one of the `allowed_scopes`.
+    pub fn requested_scopes(&self, extra: Option<&[String]>) -> Result<Vec<Scope>, ScopeNotAllowed> {
+        let mut scopes = self.scopes.clone();
+        for scope in extra.unwrap_or(&[]) {
+            if scopes.iter().any(|s| s.as_str() == scope) {
+                continue;
+            }
+            if !self.allowed_scopes.iter().any(|s| s.as_str() == scope) {
+                return Err(ScopeNotAllowed(scope.clone()));
+            }
+            scopes.push(Scope::new(scope.clone()));
+        }
+        Ok(scopes)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.client_id.is_empty() {
+            return Err(ConfigError::Invalid("client_id must not be empty".to_string()));
+        }
+        if self.access.as_ref().map_or(false, AccessPolicy::is_empty) {
+            return Err(ConfigError::Invalid(
+                "access must list allowed logins, email domains, orgs or teams".to_string()
+            ));
+        }
+        if let Some(team) = self.access.as_ref().and_then(|a| a.malformed_teams().next()) {
+            return Err(ConfigError::Invalid(format!(
+                "allowed_teams entry {:?} must be written as \"org/team-slug\"", team
+            )));
+        }
+        // Its userinfo endpoint does not say whether the email is
+        // verified, so no email is ever known.
+        if let ProviderConfig::Builtin(Provider::AzureAd { .. }) = self.oauth_provider {
+            if self.access.as_ref().map_or(false, |a| !a.allowed_email_domains.is_empty()) {
+                return Err(ConfigError::Invalid(
+                    "allowed_email_domains cannot be used with Azure AD, which does not verify emails".to_string()
+                ));
+            }
+        }
+        // The provider's endpoints are built from these values
+        // once the proxy is running, so must be valid URLs.
+        match self.oauth_provider {
+            ProviderConfig::Builtin(Provider::GithubEnterprise { ref host }) if !valid_host(host) => {
+                Err(ConfigError::Invalid(format!("invalid Github Enterprise host {:?}", host)))
+            },
+            ProviderConfig::Builtin(Provider::AzureAd { ref tenant }) if !valid_path_segment(tenant) => {
+                Err(ConfigError::Invalid(format!("invalid Azure AD tenant {:?}", tenant)))
+            },
+            ProviderConfig::Builtin(Provider::GitLab { ref base_url }) if base_url.cannot_be_a_base() => {
+                Err(ConfigError::Invalid(format!("invalid GitLab base_url {}", base_url)))
+            },
+            ProviderConfig::Registered(_) => self.oauth_provider.resolve()
+                .map(|_| ())
+                .map_err(|e| ConfigError::Invalid(e.to_string())),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Errors from loading the proxy configuration.
+#[derive(Debug, Fail)]
+pub enum ConfigError {
+    #[fail(display = "could not read config file {}: {}", path, cause)]
+    Read {
+        path: String,
+        #[cause]
+        cause: io::Error,
+    },
+
+    #[fail(display = "invalid configuration: {}", _0)]
+    Parse(#[cause] toml::de::Error),
+
+    #[fail(display = "unknown configuration key `{}`", _0)]
+    UnknownKey(String),
+
+    #[fail(display = "invalid value {:?} for `{}`", value, key)]
+    InvalidValue {
+        key: String,
+        value: String,
+    },
+
+    #[fail(display = "invalid configuration: {}", _0)]
+    Invalid(String),
+
+    #[fail(display = "could not bind to port {}: {}", port, cause)]
+    Bind {
+        port: u16,
+        #[cause]
+        cause: io::Error,
+    },
+}
+
+/// A client asked for a scope the proxy does not allow.
+#[derive(Debug, Fail)]
+#[fail(display = "scope `{}` is not allowed", _0)]
+pub struct ScopeNotAllowed(pub String);
+
+/// Whether `host` (with an optional port) is all
+/// there is to `https://{host}/`.
+fn valid_host(host: &str) -> bool {
+    match Url::parse(&format!("https://{}/", host)) {
+        Ok(url) => url.host_str().map_or(false, |h| !h.is_empty())
+            && url.username().is_empty() && url.password().is_none()
+            && url.path() == "/" && url.query().is_none() && url.fragment().is_none(),
+        Err(_) => false,
+    }
+}
+
+/// Whether `segment` is used as-is as a single path segment.
+fn valid_path_segment(segment: &str) -> bool {
+    match Url::parse(&format!("https://example.com/{}/", segment)) {
+        Ok(url) => !segment.is_empty() && url.path() == format!("/{}/", segment)
+            && url.query().is_none() && url.fragment().is_none(),
+        Err(_) => false,
+    }
+}
+
+/// Keys which may be set from the environment or overrides.
+/// Structured values (e.g. a `Custom` provider) must come from the file.
+fn is_known_key(key: &str) -> bool {
+    match key {
+        "client_id" | "client_secret" | "port" | "oauth_provider" | "proxy_url"
+            | "scopes" | "allowed_scopes" | "welcome_redirect" | "device_flow" | "pkce"
+            | "login_ttl_secs" | "handler_timeout_secs" => true,
+        _ => false,
+    }
+}
+
+/// Keys of a provider under `providers` which may be set
+/// from the environment.
+fn is_known_provider_key(key: &str) -> bool {
+    match key {
+        "client_id" | "client_secret" | "oauth_provider" | "scopes" | "allowed_scopes"
+            | "device_flow" | "pkce" => true,
+        _ => false,
+    }
+}
+
+/// Set a value of one of the `providers` in `table`, given `key` as
+/// `providers_<name>_<key>` (e.g. `providers_gitlab_client_secret`).
+///
+/// Returns `false` if no such provider is configured.
+fn set_provider_value(table: &mut Table, key: &str, value: &str) -> Result<bool, ConfigError> {
+    if !key.starts_with("providers_") {
+        return Ok(false);
+    }
+    let key = &key["providers_".len()..];
+    let providers = match table.get_mut("providers") {
+        Some(Value::Table(providers)) => providers,
+        _ => return Ok(false),
+    };
+    // Names may themselves contain `_`, so the longest match wins.
+    let found = providers.keys()
+        .filter_map(|name| {
+            let prefix = format!("{}_", name.to_lowercase().replace('-', "_"));
+            if key.starts_with(&prefix) && is_known_provider_key(&key[prefix.len()..]) {
+                Some((name.clone(), key[prefix.len()..].to_string()))
+            } else {
+                None
+            }
+        })
+        .max_by_key(|(name, _)| name.len());
+    match found {
+        Some((name, key)) => match providers.get_mut(&name) {
+            Some(Value::Table(provider)) => {
+                provider.insert(key.clone(), parse_value(&key, value)?);
+                Ok(true)
+            },
+            _ => Ok(false),
+        },
+        None => Ok(false),
+    }
+}
+
+/// Convert a string value into the TOML type expected for `key`.
+fn parse_value(key: &str, value: &str) -> Result<Value, ConfigError> {
+    let invalid = || ConfigError::InvalidValue {
+        key: key.to_string(),
+        value: value.to_string(),
+    };
+    Ok(match key {
+        "port" | "login_ttl_secs" | "handler_timeout_secs" =>
+            Value::Integer(value.parse().map_err(|_| invalid())?),
+        "device_flow" | "pkce" => Value::Boolean(value.parse().map_err(|_| invalid())?),
+        "scopes" | "allowed_scopes" => Value::Array(
+            value.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| Value::String(s.to_string()))
+                .collect()
+        ),
+        _ => Value::String(value.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(settings: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(&format!(r#"
+            client_id = "client"
+            client_secret = "secret"
+            port = 8081
+            proxy_url = "http://localhost:8081/"
+            welcome_redirect = "http://localhost:8080/"
+            {}
+        "#, settings))
+    }
+
+    #[test]
+    fn builtin_provider() {
+        let config = config(r#"oauth_provider = "Github""#).unwrap();
+        match config.oauth_provider {
+            ProviderConfig::Builtin(Provider::Github) => {},
+            other => panic!("unexpected provider {:?}", other),
+        }
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn unknown_provider_is_rejected() {
+        let config = config(r#"oauth_provider = "Gihub""#).unwrap();
+        match config.validate() {
+            Err(ConfigError::Invalid(msg)) => assert!(msg.contains("unknown provider `Gihub`"), "{}", msg),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn malformed_provider_says_why() {
+        let e = config(r#"
+            [oauth_provider.Custom]
+            auth_url = "https://auth.example.com/authorize"
+        "#).unwrap_err().to_string();
+        assert!(e.contains("invalid oauth_provider"), "{}", e);
+        assert!(e.contains("token_url"), "{}", e);
+
+        let e = config(r#"oauth_provider = "GithubEnterprise""#).unwrap_err().to_string();
+        assert!(e.contains("invalid oauth_provider"), "{}", e);
+    }
+
+    #[test]
+    fn access_is_per_provider() {
+        let config = config(r#"
+            oauth_provider = "Github"
+
+            [access]
+            allowed_orgs = ["my-org"]
+
+            [providers.gitlab]
+            client_id = "gitlab-client"
+            client_secret = "gitlab-secret"
+            oauth_provider = "GitLab"
+
+            [providers.gitlab.access]
+            allowed_teams = ["my-team"]
+        "#).unwrap();
+        assert_eq!(config.default_provider().access.unwrap().allowed_orgs, vec!["my-org"]);
+        assert!(config.providers["gitlab"].access.as_ref().unwrap().allowed_orgs.is_empty());
+        match config.validate() {
+            Err(ConfigError::Invalid(msg)) => assert!(msg.starts_with("providers.gitlab: allowed_teams"), "{}", msg),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn access_must_allow_someone() {
+        let config = config(r#"
+            oauth_provider = "Github"
+
+            [access]
+            allowed_orgs = []
+        "#).unwrap();
+        match config.validate() {
+            Err(ConfigError::Invalid(msg)) => assert!(msg.starts_with("access must list"), "{}", msg),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn provider_values_must_make_urls() {
+        for provider in &[
+            r#"[oauth_provider.GithubEnterprise]
+            host = "github example.com""#,
+            r#"[oauth_provider.GithubEnterprise]
+            host = "github.example.com:99999""#,
+            r#"[oauth_provider.GithubEnterprise]
+            host = "github.example.com/path""#,
+            r#"[oauth_provider.GithubEnterprise]
+            host = "user@github.example.com""#,
+            r#"[oauth_provider.AzureAd]
+            tenant = "contoso tenant""#,
+            r#"[oauth_provider.AzureAd]
+            tenant = "contoso?x=1""#,
+        ] {
+            match config(provider).unwrap().validate() {
+                Err(ConfigError::Invalid(_)) => {},
+                other => panic!("unexpected result {:?} for {}", other, provider),
+            }
+        }
+
+        for provider in &[
+            r#"[oauth_provider.GithubEnterprise]
+            host = "github.example.com:8443""#,
+            r#"[oauth_provider.AzureAd]
+            tenant = "contoso.onmicrosoft.com""#,
+        ] {
+            config(provider).unwrap().validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn azure_ad_cannot_allow_email_domains() {
+        let config = config(r#"
+            [oauth_provider.AzureAd]
+            tenant = "contoso.onmicrosoft.com"
+
+            [access]
+            allowed_email_domains = ["contoso.com"]
+        "#).unwrap();
+        match config.validate() {
+            Err(ConfigError::Invalid(msg)) => assert!(msg.contains("allowed_email_domains"), "{}", msg),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn provider_values_from_environment() {
+        let path = env::temp_dir().join(format!("olaf2-config-test-{}.toml", std::process::id()));
+        fs::write(&path, r#"
+            client_id = "client"
+            client_secret = "secret"
+            port = 8081
+            proxy_url = "http://localhost:8081/"
+            welcome_redirect = "http://localhost:8080/"
+            oauth_provider = "Github"
+
+            [providers.corp-sso]
+            client_id = "sso-client"
+            client_secret = "from-file"
+            oauth_provider = "Google"
+        "#).unwrap();
+        env::set_var("OLAF2_PROVIDERS_CORP_SSO_CLIENT_SECRET", "from-env");
+        env::set_var("OLAF2_PROVIDERS_CORP_SSO_SCOPES", "openid, email");
+        let config = Config::load(Some(&path), &[]);
+        env::remove_var("OLAF2_PROVIDERS_CORP_SSO_CLIENT_SECRET");
+        env::remove_var("OLAF2_PROVIDERS_CORP_SSO_SCOPES");
+        fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        let sso = &config.providers["corp-sso"];
+        assert_eq!(sso.client_id.as_str(), "sso-client");
+        assert_eq!(sso.client_secret.secret(), "from-env");
+        let scopes: Vec<&str> = sso.scopes.iter().map(|s| s.as_str()).collect();
+        assert_eq!(scopes, vec!["openid", "email"]);
+        assert_eq!(config.client_secret.secret(), "secret");
+    }
+}
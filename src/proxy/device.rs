@@ -0,0 +1,236 @@
+//! Device authorization grant ([RFC 8628](https://tools.ietf.org/html/rfc8628)).
+//!
+//! Rather than redirecting the browser back to the client, the
+//! `User` enters a short code on the provider's website, while the
+//! client polls the proxy until the authorization completes.
+
+use ::actix::prelude::*;
+use actix_web::{http, AsyncResponder, FutureResponse, HttpResponse, Json, State};
+use failure::{format_err, Error};
+use futures::future::{self, Either};
+use futures::prelude::*;
+use log::*;
+use oauth2::prelude::*;
+use reqwest::header::ACCEPT;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_derive::Deserialize;
+
+use std::fmt::Debug;
+use std::time::SystemTime;
+
+use super::exchange::with_claims;
+use super::{new_session, AppState, OAuthExecutor, ScopeNotAllowed, SessionHandler, TokenInfo};
+use crate::msgs::*;
+
+/// Grant type used when polling the token endpoint.
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// Request a device code from the provider, for
+/// the extra scopes asked for by the client.
+pub(crate) struct DeviceStart(pub Option<Vec<String>>);
+
+impl Message for DeviceStart {
+    type Result = Result<DeviceStartResponse, Error>;
+}
+
+impl Handler<DeviceStart> for OAuthExecutor {
+    type Result = Result<DeviceStartResponse, Error>;
+
+    fn handle(&mut self, msg: DeviceStart, _: &mut Self::Context) -> Self::Result {
+        let settings = &self.settings;
+        let device_url = match self.provider.device_auth_url() {
+            Some(ref url) if settings.device_flow => url.clone(),
+            _ => return Err(format_err!("device flow is not enabled")),
+        };
+        let scopes = settings.requested_scopes(msg.0.as_ref().map(Vec::as_slice))?
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut resp = self.http
+            .post(device_url)
+            .header(ACCEPT, "application/json")
+            .form(&[("client_id", settings.client_id.as_str()), ("scope", scopes.as_str())])
+            .send()?;
+        if !resp.status().is_success() {
+            return Err(format_err!("device authorization failed: {}", resp.status()));
+        }
+        Ok(resp.json()?)
+    }
+}
+
+/// State of a pending device authorization.
+pub(crate) enum DeviceStatus {
+    Pending,
+    SlowDown,
+    Denied,
+    Expired,
+    Complete(TokenInfo),
+}
+
+/// Error body from the token endpoint while polling,
+/// see [RFC 8628 section 3.5](https://tools.ietf.org/html/rfc8628#section-3.5).
+#[derive(Deserialize)]
+struct DeviceError {
+    error: String,
+}
+
+/// Check whether the `User` has completed the device authorization
+/// started with the given extra scopes.
+pub(crate) struct DevicePoll(pub String, pub Option<Vec<String>>);
+
+impl Message for DevicePoll {
+    type Result = Result<DeviceStatus, Error>;
+}
+
+impl Handler<DevicePoll> for OAuthExecutor {
+    type Result = Result<DeviceStatus, Error>;
+
+    fn handle(&mut self, msg: DevicePoll, _: &mut Self::Context) -> Self::Result {
+        self.device_poll(msg)
+    }
+}
+
+impl OAuthExecutor {
+    pub(super) fn device_poll(&self, msg: DevicePoll) -> Result<DeviceStatus, Error> {
+        let (_, body) = self.post_token(&[
+            ("grant_type", DEVICE_CODE_GRANT),
+            ("device_code", msg.0.as_str()),
+        ])?;
+        // Some providers (e.g. Github) report errors with a 200 status,
+        // so check the body for an error first.
+        if let Ok(DeviceError { error }) = serde_json::from_value(body.clone()) {
+            return match error.as_str() {
+                "authorization_pending" => Ok(DeviceStatus::Pending),
+                "slow_down" => Ok(DeviceStatus::SlowDown),
+                "access_denied" => Ok(DeviceStatus::Denied),
+                "expired_token" => Ok(DeviceStatus::Expired),
+                other => Err(format_err!("device token request failed: {}", other)),
+            };
+        }
+        let token = self.provider.parse_token_response(&body)?;
+        // No nonce can be sent with a device authorization request,
+        // but the rest of the id_token is still checked.
+        let claims = self.id_token_claims(&body, None)?;
+        let scopes = self.settings.requested_scopes(msg.1.as_ref().map(Vec::as_slice))?;
+        let info = self.token_info(token, scopes, None, SystemTime::now())?;
+        Ok(DeviceStatus::Complete(with_claims(info, claims)?))
+    }
+}
+
+/// Starts a device authorization grant, returning the user code
+/// and verification URL for the client to show the `User`.
+pub(crate) fn device_start<H, R>((params, state): (Json<DeviceStartParams>, State<AppState<H, R>>))
+    -> FutureResponse<HttpResponse>
+    where H: SessionHandler<R>,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize,
+{
+    let DeviceStartParams { provider, scopes, .. } = params.into_inner();
+    let oauth_client = match state.executor(provider.as_ref()) {
+        Some(client) => client,
+        None => return Box::new(future::ok(HttpResponse::NotFound().finish())),
+    };
+    oauth_client
+        .send(DeviceStart(scopes))
+        .from_err::<Error>()
+        .and_then(|res| match res {
+            Ok(resp) => Ok(HttpResponse::Ok().json(resp)),
+            Err(ref e) if e.downcast_ref::<ScopeNotAllowed>().is_some() => {
+                Ok(HttpResponse::BadRequest().body(e.to_string()))
+            },
+            Err(e) => {
+                warn!("Could not start device flow: {}", e);
+                Ok(HttpResponse::NotFound().finish())
+            },
+        }).responder()
+}
+
+/// Polls for completion of a device authorization grant.
+///
+/// Responds with the `FinResponse` as JSON once the `User` has
+/// authorized, `202 Accepted` while pending, `429 Too Many Requests`
+/// if the client should poll more slowly, `403 Forbidden` if the
+/// `User` denied the request (or is not allowed to log in), and
+/// `410 Gone` if the code expired.
+pub(crate) fn device_poll<H, R>((params, state): (Json<DevicePollParams>, State<AppState<H, R>>))
+    -> FutureResponse<HttpResponse>
+    where H: SessionHandler<R>,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize,
+{
+    let DevicePollParams { csrf_token, device_code, provider, scopes } = params.into_inner();
+    let oauth_client = match state.executor(provider.as_ref()) {
+        Some(client) => client.clone(),
+        None => return Box::new(future::ok(HttpResponse::NotFound().finish())),
+    };
+    oauth_client
+        .send(DevicePoll(device_code, scopes))
+        .from_err::<Error>()
+        .and_then(move |res| match res {
+            Ok(DeviceStatus::Complete(token)) => {
+                if let Err(e) = state.check_access(provider.as_ref(), &token) {
+                    warn!("Denied device login: {}", e);
+                    return Either::B(future::ok(HttpResponse::Forbidden().finish()));
+                }
+                let refresh_token = token.refresh_token.clone();
+                Either::A(new_session(&state, token, csrf_token, provider, refresh_token)
+                    .map(|resp| match resp {
+                        Some(resp) => HttpResponse::Ok().json(resp),
+                        None => HttpResponse::InternalServerError().finish(),
+                    }))
+            },
+            Ok(DeviceStatus::Pending) => Either::B(future::ok(HttpResponse::Accepted().finish())),
+            Ok(DeviceStatus::SlowDown) => Either::B(future::ok(
+                HttpResponse::build(http::StatusCode::TOO_MANY_REQUESTS).finish()
+            )),
+            Ok(DeviceStatus::Denied) => Either::B(future::ok(HttpResponse::Forbidden().finish())),
+            Ok(DeviceStatus::Expired) => Either::B(future::ok(HttpResponse::Gone().finish())),
+            Err(e) => {
+                warn!("Device token request failed: {}", e);
+                Either::B(future::ok(HttpResponse::BadGateway().finish()))
+            },
+        }).responder()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::MockServer;
+    use crate::proxy::{Config, MemoryPendingLoginStore};
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn oidc_device_login_needs_an_id_token() {
+        let server = MockServer::start(&[
+            ("/token", r#"{"access_token": "token", "token_type": "bearer"}"#),
+        ]);
+        let issuer = server.url("/");
+        server.route("/.well-known/openid-configuration", &format!(r#"{{
+            "issuer": "{}",
+            "authorization_endpoint": "{}",
+            "token_endpoint": "{}",
+            "jwks_uri": "{}",
+            "device_authorization_endpoint": "{}"
+        }}"#, issuer, server.url("authorize"), server.url("token"), server.url("jwks"), server.url("device")));
+        let config: Config = toml::from_str(&format!(r#"
+            client_id = "client"
+            client_secret = "secret"
+            port = 8081
+            proxy_url = "http://localhost:8081/"
+            welcome_redirect = "http://localhost:8080/"
+            device_flow = true
+
+            [oauth_provider.Oidc]
+            issuer = "{}"
+        "#, issuer)).unwrap();
+        let settings = config.default_provider();
+        let pending = Arc::new(MemoryPendingLoginStore::new(Duration::from_secs(600)));
+        let executor = OAuthExecutor::new(config, None, settings, pending).unwrap();
+
+        match executor.device_poll(DevicePoll("device-code".to_string(), None)) {
+            Err(e) => assert_eq!(e.to_string(), "token response has no id_token"),
+            Ok(_) => panic!("device login completed without an id_token"),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Requests to the provider's token endpoint.
+//!
+//! These are made directly, rather than through `BasicClient`, so that
+//! the `OAuthProvider` decides how the proxy authenticates and how the
+//! response is parsed. It also keeps hold of the whole response, since
+//! `BasicTokenResponse` drops the `id_token`.
+
+use ::actix::prelude::*;
+use failure::{format_err, Error, Fail};
+use oauth2::basic::BasicTokenResponse;
+use oauth2::prelude::*;
+use oauth2::{AuthType, AuthorizationCode};
+use reqwest::header::ACCEPT;
+use reqwest::StatusCode;
+use serde_json::Value;
+
+use super::{OAuthExecutor, PendingLogin, TokenInfo};
+use crate::server::oidc::{discover, validate_id_token, Claims};
+use crate::server::Identity;
+
+/// The token endpoint responded with an error.
+#[derive(Debug, Fail)]
+#[fail(display = "token request failed ({}): {}", status, body)]
+pub(super) struct TokenRequestFailed {
+    pub status: StatusCode,
+    pub body: Value,
+}
+
+impl TokenRequestFailed {
+    /// The error code, see RFC 6749 section 5.2.
+    pub fn error(&self) -> Option<&str> {
+        self.body.get("error").and_then(Value::as_str)
+    }
+}
+
+/// Exchange the authorization `code` for a finished `login`.
+pub(super) struct Exchange {
+    pub code: AuthorizationCode,
+    pub login: PendingLogin,
+}
+
+impl Message for Exchange {
+    type Result = Result<TokenInfo, Error>;
+}
+
+impl Handler<Exchange> for OAuthExecutor {
+    type Result = Result<TokenInfo, Error>;
+
+    fn handle(&mut self, msg: Exchange, _: &mut Self::Context) -> Self::Result {
+        self.exchange(msg)
+    }
+}
+
+impl OAuthExecutor {
+    pub(super) fn exchange(&self, msg: Exchange) -> Result<TokenInfo, Error> {
+        let Exchange { code, login } = msg;
+        // The redirect URL must match the one used to authorize.
+        let mut params = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code.secret().as_str()),
+            ("redirect_uri", login.redirect_url.as_str()),
+        ];
+        if let Some(ref verifier) = login.pkce_verifier {
+            params.push(("code_verifier", verifier.secret().as_str()));
+        }
+        let (token, body) = self.request_token(&params)?;
+
+        if self.provider.issuer().is_some() && login.nonce.is_none() {
+            return Err(format_err!("login was started without a nonce"));
+        }
+        let claims = self.id_token_claims(&body, login.nonce.as_ref().map(String::as_str))?;
+
+        let info = self.token_info(token, login.scopes, login.client_port, login.created_at)?;
+        with_claims(info, claims)
+    }
+
+    /// Validate the `id_token` in the token response `body`, if the
+    /// provider is an OpenID Connect one.
+    ///
+    /// `nonce` is the one sent with the login, if any.
+    pub(super) fn id_token_claims(&self, body: &Value, nonce: Option<&str>) -> Result<Option<Claims>, Error> {
+        match self.provider.issuer() {
+            Some(issuer) => {
+                let id_token = body.get("id_token").and_then(Value::as_str)
+                    .ok_or_else(|| format_err!("token response has no id_token"))?;
+                let discovery = discover(&issuer)?;
+                Ok(Some(validate_id_token(&discovery, id_token, self.settings.client_id.as_str(), nonce)?))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// POST `params` to the token endpoint, authenticating as
+    /// the client as the provider expects.
+    ///
+    /// Returns the status along with the JSON body, which may
+    /// describe an error.
+    pub(super) fn post_token(&self, params: &[(&str, &str)]) -> Result<(StatusCode, Value), Error> {
+        let client_id = self.settings.client_id.as_str();
+        let client_secret = self.settings.client_secret.secret().as_str();
+        let mut form = params.to_vec();
+        let mut request = self.http
+            .post(self.token_url.as_str())
+            .header(ACCEPT, "application/json");
+        match self.provider.auth_type() {
+            AuthType::BasicAuth => request = request.basic_auth(client_id, Some(client_secret)),
+            AuthType::RequestBody => {
+                form.push(("client_id", client_id));
+                form.push(("client_secret", client_secret));
+            },
+        }
+        let mut resp = request.form(&form).send()?;
+        Ok((resp.status(), resp.json()?))
+    }
+
+    /// Request a token with `params`, returning the parsed
+    /// token along with the full response.
+    pub(super) fn request_token(&self, params: &[(&str, &str)]) -> Result<(BasicTokenResponse, Value), Error> {
+        let (status, body) = self.post_token(params)?;
+        // Some providers (e.g. Github) report errors with a 200 status.
+        if body.get("error").is_some() || !status.is_success() {
+            return Err(TokenRequestFailed { status, body }.into());
+        }
+        let token = self.provider.parse_token_response(&body)?;
+        Ok((token, body))
+    }
+}
+
+/// Add the validated `claims` of an `id_token` to `info`.
+pub(super) fn with_claims(mut info: TokenInfo, claims: Option<Claims>) -> Result<TokenInfo, Error> {
+    if let Some(claims) = claims {
+        // Without a userinfo endpoint, the id_token is all we know.
+        if info.identity.is_none() {
+            info.identity = Some(Identity::from_claims(&Value::Object(claims.clone()))?);
+        }
+        info.id_token_claims = Some(claims);
+    }
+    Ok(info)
+}
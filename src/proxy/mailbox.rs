@@ -0,0 +1,169 @@
+//! Proxy-side mailbox for `Delivery::Poll`.
+//!
+//! The finish handler deposits the serialized `FinResponse` under the
+//! client's CSRF `state`, where the client collects it by long-polling
+//! `/oauth-cli/poll`. The `state` appears in the authorization URL, so
+//! collecting also needs the `client_key` the login was started with.
+
+use ::actix::prelude::*;
+use failure::{format_err, Error};
+use futures::sync::oneshot;
+
+use std::collections::hash_map::{Entry as MapEntry, HashMap};
+use std::time::{Duration, Instant};
+
+/// How long (in seconds) a mailbox is kept open waiting for the
+/// login to finish, and for the client to collect the response.
+const MAILBOX_TTL_SECS: u64 = 600;
+
+enum Slot {
+    /// Waiting for a response, with any clients currently polling.
+    Empty(Vec<oneshot::Sender<String>>),
+    /// Response ready for collection.
+    Full(String),
+}
+
+struct Entry {
+    created: Instant,
+    client_key: Option<String>,
+    slot: Slot,
+}
+
+/// Actor holding responses keyed by CSRF `state`.
+#[derive(Default)]
+pub(crate) struct Mailbox {
+    entries: HashMap<String, Entry>,
+}
+
+impl Mailbox {
+    /// Drop any mailboxes older than the TTL.
+    fn expire(&mut self) {
+        let ttl = Duration::from_secs(MAILBOX_TTL_SECS);
+        self.entries.retain(|_, entry| entry.created.elapsed() < ttl);
+    }
+}
+
+impl Actor for Mailbox {
+    type Context = Context<Self>;
+}
+
+/// Create an empty mailbox for `state`, to be collected
+/// with `client_key`.
+///
+/// Fails if there is already a mailbox for `state`, which
+/// may belong to another client.
+pub(crate) struct Open {
+    pub state: String,
+    pub client_key: Option<String>,
+}
+
+impl Message for Open {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<Open> for Mailbox {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: Open, _: &mut Self::Context) -> Self::Result {
+        self.expire();
+        let Open { state, client_key } = msg;
+        match self.entries.entry(state) {
+            MapEntry::Occupied(_) => Err(format_err!("a mailbox is already open for this state")),
+            MapEntry::Vacant(entry) => {
+                entry.insert(Entry { created: Instant::now(), client_key, slot: Slot::Empty(vec![]) });
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Deposit the serialized response for `state`.
+pub(crate) struct Deposit {
+    pub state: String,
+    pub body: String,
+}
+
+impl Message for Deposit {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<Deposit> for Mailbox {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: Deposit, _: &mut Self::Context) -> Self::Result {
+        self.expire();
+        let Deposit { state, body } = msg;
+        let waiters = match self.entries.get_mut(&state) {
+            Some(Entry { slot: Slot::Empty(waiters), .. }) => ::std::mem::replace(waiters, vec![]),
+            Some(_) => return Err(format_err!("response already deposited")),
+            None => return Err(format_err!("no mailbox open for this state")),
+        };
+        // Hand the response straight to a polling client if there is one
+        // still listening, otherwise keep it for collection.
+        let mut body = Some(body);
+        for waiter in waiters {
+            match waiter.send(body.take().unwrap()) {
+                Ok(()) => break,
+                Err(returned) => body = Some(returned),
+            }
+        }
+        match body {
+            Some(body) => {
+                if let Some(entry) = self.entries.get_mut(&state) {
+                    entry.slot = Slot::Full(body);
+                }
+            },
+            None => { self.entries.remove(&state); },
+        }
+        Ok(())
+    }
+}
+
+/// Collect the response for `state`, presenting the
+/// `client_key` the mailbox was opened with.
+pub(crate) struct Collect {
+    pub state: String,
+    pub client_key: Option<String>,
+}
+
+/// Result of collecting from a mailbox.
+pub(crate) enum Collected {
+    /// The response was waiting.
+    Ready(String),
+    /// Not yet available; resolves when the response is deposited.
+    Pending(oneshot::Receiver<String>),
+}
+
+impl Message for Collect {
+    type Result = Result<Collected, Error>;
+}
+
+impl Handler<Collect> for Mailbox {
+    type Result = Result<Collected, Error>;
+
+    fn handle(&mut self, msg: Collect, _: &mut Self::Context) -> Self::Result {
+        self.expire();
+        let Collect { state, client_key } = msg;
+        // Turned away without disturbing the mailbox, so the
+        // client can still collect its response.
+        match self.entries.get(&state) {
+            Some(entry) if entry.client_key != client_key => {
+                return Err(format_err!("wrong client_key for this state"));
+            },
+            _ => {},
+        }
+        // Responses are single-use, so are removed once collected.
+        match self.entries.remove(&state) {
+            Some(Entry { slot: Slot::Full(body), .. }) => Ok(Collected::Ready(body)),
+            Some(mut entry) => {
+                let (tx, rx) = oneshot::channel();
+                if let Slot::Empty(ref mut waiters) = entry.slot {
+                    waiters.push(tx);
+                }
+                self.entries.insert(state, entry);
+                Ok(Collected::Pending(rx))
+            },
+            None => Err(format_err!("unknown or expired state")),
+        }
+    }
+}
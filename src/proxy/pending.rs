@@ -0,0 +1,205 @@
+//! Server-side record of logins in progress.
+//!
+//! `/oauth-cli/start` records each login under its CSRF `state`,
+//! and `/oauth-cli/finish` only proceeds if the `state` it receives
+//! was issued, has not expired, and has not been used before.
+
+use failure::{Error, Fail};
+use oauth2::{PkceCodeVerifierS256, RedirectUrl, Scope};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::msgs::Delivery;
+
+/// A login which has been started, but not yet finished.
+#[derive(Clone, Debug)]
+pub struct PendingLogin {
+    /// Redirect URL used when authorizing; must be sent again
+    /// when exchanging the code.
+    pub redirect_url: RedirectUrl,
+
+    /// How to deliver the response to the client.
+    pub delivery: Delivery,
+
+    /// Name of the provider the login was started with,
+    /// or `None` for the default provider.
+    pub provider: Option<String>,
+
+    /// Port of the client's local listener, for `Delivery::Listener`.
+    pub client_port: Option<u16>,
+
+    /// PKCE code verifier, if PKCE is in use.
+    pub pkce_verifier: Option<PkceCodeVerifierS256>,
+
+    /// Nonce the `id_token` must carry, for OpenID Connect providers.
+    pub nonce: Option<String>,
+
+    /// Key to seal the response to, if the client sent one.
+    pub client_key: Option<String>,
+
+    /// Scopes requested in the authorization URL.
+    pub scopes: Vec<Scope>,
+
+    pub created_at: SystemTime,
+}
+
+/// Reasons a `state` is rejected at `/oauth-cli/finish`.
+#[derive(Debug, Fail)]
+pub enum StateError {
+    #[fail(display = "this login was not started by the proxy")]
+    Unknown,
+
+    #[fail(display = "this login has expired")]
+    Expired,
+
+    #[fail(display = "this login has already been completed")]
+    Replayed,
+
+    #[fail(display = "a login has already been started with this state")]
+    Duplicate,
+
+    #[fail(display = "could not look up login: {}", _0)]
+    Store(Error),
+}
+
+/// Storage for logins in progress, keyed by `state`.
+///
+/// Implementations must be safe to share between the proxy's
+/// worker threads.
+pub trait PendingLoginStore: Send + Sync {
+    /// Record a new login under `state`.
+    ///
+    /// The `state` comes from the client, so must be rejected (with
+    /// `StateError::Duplicate`) if it has been seen before: it may
+    /// belong to another login, or one which was already completed.
+    fn insert(&self, state: &str, login: PendingLogin) -> Result<(), Error>;
+
+    /// Remove and return the login for `state`.
+    /// Each `state` may only be taken once.
+    fn take(&self, state: &str) -> Result<PendingLogin, StateError>;
+}
+
+enum Entry {
+    Pending(PendingLogin),
+    /// Tombstone for a completed login, kept to detect replays.
+    Used(SystemTime),
+}
+
+impl Entry {
+    fn created_at(&self) -> SystemTime {
+        match self {
+            Entry::Pending(login) => login.created_at,
+            Entry::Used(created_at) => *created_at,
+        }
+    }
+}
+
+/// In-memory `PendingLoginStore`, where logins expire after a TTL.
+pub struct MemoryPendingLoginStore {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl MemoryPendingLoginStore {
+    pub fn new(ttl: Duration) -> Self {
+        MemoryPendingLoginStore {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn age(&self, created_at: SystemTime) -> Duration {
+        // A clock going backwards counts as brand new.
+        created_at.elapsed().unwrap_or_else(|_| Duration::from_secs(0))
+    }
+}
+
+impl PendingLoginStore for MemoryPendingLoginStore {
+    fn insert(&self, state: &str, login: PendingLogin) -> Result<(), Error> {
+        let mut entries = self.entries.lock().unwrap();
+        // Entries are kept for twice the TTL, so that late requests
+        // are reported as expired (or replayed) rather than unknown.
+        let keep = self.ttl * 2;
+        entries.retain(|_, entry| self.age(entry.created_at()) < keep);
+        if entries.contains_key(state) {
+            return Err(StateError::Duplicate.into());
+        }
+        entries.insert(state.to_string(), Entry::Pending(login));
+        Ok(())
+    }
+
+    fn take(&self, state: &str) -> Result<PendingLogin, StateError> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.remove(state).ok_or(StateError::Unknown)?;
+        let created_at = entry.created_at();
+        entries.insert(state.to_string(), Entry::Used(created_at));
+        match entry {
+            Entry::Used(_) => Err(StateError::Replayed),
+            Entry::Pending(_) if self.age(created_at) >= self.ttl => Err(StateError::Expired),
+            Entry::Pending(login) => Ok(login),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login() -> PendingLogin {
+        PendingLogin {
+            redirect_url: RedirectUrl::new(url::Url::parse("http://localhost:8081/oauth-cli/finish").unwrap()),
+            delivery: Delivery::Listener,
+            provider: None,
+            client_port: Some(1234),
+            pkce_verifier: None,
+            nonce: None,
+            client_key: None,
+            scopes: Vec::new(),
+            created_at: SystemTime::now(),
+        }
+    }
+
+    fn is_duplicate(res: Result<(), Error>) -> bool {
+        match res {
+            Err(e) => match e.downcast_ref::<StateError>() {
+                Some(StateError::Duplicate) => true,
+                _ => false,
+            },
+            Ok(()) => false,
+        }
+    }
+
+    #[test]
+    fn state_is_taken_once() {
+        let store = MemoryPendingLoginStore::new(Duration::from_secs(600));
+        store.insert("state", login()).unwrap();
+        assert_eq!(store.take("state").unwrap().client_port, Some(1234));
+        match store.take("state") {
+            Err(StateError::Replayed) => {},
+            res => panic!("expected a replay, got {:?}", res),
+        }
+        match store.take("other") {
+            Err(StateError::Unknown) => {},
+            res => panic!("expected an unknown state, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn existing_state_is_not_replaced() {
+        let store = MemoryPendingLoginStore::new(Duration::from_secs(600));
+        store.insert("pending", login()).unwrap();
+        let mut other = login();
+        other.client_port = Some(4321);
+        assert!(is_duplicate(store.insert("pending", other.clone())));
+        assert_eq!(store.take("pending").unwrap().client_port, Some(1234));
+
+        // Nor can a completed login be started again.
+        assert!(is_duplicate(store.insert("pending", other)));
+        match store.take("pending") {
+            Err(StateError::Replayed) => {},
+            res => panic!("expected a replay, got {:?}", res),
+        }
+    }
+}
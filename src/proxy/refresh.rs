@@ -0,0 +1,156 @@
+//! Refreshing sessions without the `User`.
+//!
+//! Refresh tokens never leave the proxy. Instead the client is given an
+//! opaque handle, which it presents at `/oauth-cli/refresh` to have the
+//! proxy redeem the refresh token and run the `SessionHandler` again.
+//!
+//! Handles are single-use: each refresh issues a new one. They are only
+//! kept in memory, so a restart of the proxy sends clients back through
+//! the browser flow.
+
+use ::actix::prelude::*;
+use actix_web::{AsyncResponder, FutureResponse, HttpResponse, Json, State};
+use failure::Error;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use log::*;
+use oauth2::prelude::*;
+use oauth2::{CsrfToken, RefreshToken, Scope};
+use serde::{de::DeserializeOwned, Serialize};
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use super::exchange::TokenRequestFailed;
+use super::{new_session, AppState, OAuthExecutor, SessionHandler, TokenInfo};
+use crate::msgs::*;
+
+/// How long (in seconds) an unused refresh handle is kept.
+const REFRESH_HANDLE_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// A refresh token held by the proxy.
+pub(crate) struct Entry {
+    pub token: RefreshToken,
+    /// Name of the provider which issued the token,
+    /// or `None` for the default provider.
+    pub provider: Option<String>,
+    /// Scopes of the session the token belongs to.
+    pub scopes: Vec<Scope>,
+    issued_at: SystemTime,
+}
+
+/// Refresh tokens held by the proxy, keyed by opaque handle.
+#[derive(Default)]
+pub(crate) struct RefreshHandles {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl RefreshHandles {
+    /// Store `token`, issued by `provider` for `scopes`,
+    /// returning a new handle for it.
+    pub fn issue(&self, token: RefreshToken, provider: Option<String>, scopes: Vec<Scope>) -> String {
+        let handle = CsrfToken::new_random().secret().to_string();
+        let now = SystemTime::now();
+        let ttl = Duration::from_secs(REFRESH_HANDLE_TTL_SECS);
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, e| now.duration_since(e.issued_at).map_or(true, |age| age < ttl));
+        entries.insert(handle.clone(), Entry { token, provider, scopes, issued_at: now });
+        handle
+    }
+
+    /// Remove and return the entry for `handle`.
+    pub fn take(&self, handle: &str) -> Option<Entry> {
+        let ttl = Duration::from_secs(REFRESH_HANDLE_TTL_SECS);
+        self.entries.lock().unwrap()
+            .remove(handle)
+            .filter(|e| e.issued_at.elapsed().map_or(true, |age| age < ttl))
+    }
+
+    /// Put back the `entry` taken for `handle`, when it could not be
+    /// redeemed for reasons other than the token being rejected.
+    pub fn restore(&self, handle: String, entry: Entry) {
+        self.entries.lock().unwrap().insert(handle, entry);
+    }
+}
+
+/// Redeem a refresh token with the provider,
+/// for a session with the given scopes.
+pub(crate) struct Refresh(pub RefreshToken, pub Vec<Scope>);
+
+impl Message for Refresh {
+    type Result = Result<TokenInfo, Error>;
+}
+
+impl Handler<Refresh> for OAuthExecutor {
+    type Result = Result<TokenInfo, Error>;
+
+    fn handle(&mut self, msg: Refresh, _: &mut Self::Context) -> Self::Result {
+        let (token, _) = self.request_token(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", msg.0.secret().as_str()),
+        ])?;
+        self.token_info(token, msg.1, None, SystemTime::now())
+    }
+}
+
+/// Mints a new session from the refresh token behind the client's handle.
+///
+/// Responds with the `FinResponse` as JSON, including a new handle,
+/// `403 Forbidden` if the `User` is no longer allowed to log in,
+/// `410 Gone` if the handle is unknown or the provider no longer
+/// accepts the refresh token, or `502 Bad Gateway` if the provider
+/// could not refresh it for another reason. In the last case, the
+/// handle may be tried again.
+pub(crate) fn oauth_refresh<H, R>((params, state): (Json<RefreshParams>, State<AppState<H, R>>))
+    -> FutureResponse<HttpResponse>
+    where H: SessionHandler<R>,
+          R: 'static + Debug + DeserializeOwned + Send + Serialize,
+{
+    let RefreshParams { csrf_token, refresh_handle } = params.into_inner();
+    // Taken now, so the handle cannot be redeemed twice at once.
+    let entry = match state.refresh.take(&refresh_handle) {
+        Some(entry) => entry,
+        None => return Box::new(future::ok(HttpResponse::Gone().finish())),
+    };
+    // e.g. the provider was removed from the configuration.
+    let oauth_client = match state.executor(entry.provider.as_ref()) {
+        Some(client) => client.clone(),
+        None => return Box::new(future::ok(HttpResponse::Gone().finish())),
+    };
+    oauth_client
+        .send(Refresh(entry.token.clone(), entry.scopes.clone()))
+        .from_err::<Error>()
+        .and_then(move |res| match res {
+            Ok(token) => {
+                let Entry { token: refresh_token, provider, .. } = entry;
+                // Access may have been revoked since the last login.
+                if let Err(e) = state.check_access(provider.as_ref(), &token) {
+                    warn!("Denied refresh: {}", e);
+                    return Either::B(future::ok(HttpResponse::Forbidden().finish()));
+                }
+                // Providers which do not rotate refresh tokens
+                // leave the old one valid.
+                let refresh_token = token.refresh_token.clone().unwrap_or(refresh_token);
+                Either::A(new_session(&state, token, csrf_token, provider, Some(refresh_token))
+                    .map(|resp| match resp {
+                        Some(resp) => HttpResponse::Ok().json(resp),
+                        None => HttpResponse::InternalServerError().finish(),
+                    }))
+            },
+            Err(e) => {
+                warn!("Could not refresh session: {}", e);
+                // See RFC 6749 section 5.2. Anything else (e.g. the
+                // provider being unreachable) may be temporary.
+                let rejected = e.downcast_ref::<TokenRequestFailed>()
+                    .map_or(false, |e| e.error() == Some("invalid_grant"));
+                if rejected {
+                    Either::B(future::ok(HttpResponse::Gone().finish()))
+                } else {
+                    state.refresh.restore(refresh_handle, entry);
+                    Either::B(future::ok(HttpResponse::BadGateway().finish()))
+                }
+            },
+        }).responder()
+}
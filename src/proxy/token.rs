@@ -0,0 +1,105 @@
+//! Details of a completed login, as passed to the `SessionHandler`.
+
+use oauth2::basic::{BasicTokenResponse, BasicTokenType};
+use oauth2::prelude::*;
+use oauth2::{AccessToken, RefreshToken, Scope};
+
+use std::ops::Deref;
+use std::time::{Duration, SystemTime};
+
+use crate::server::oidc::Claims;
+use crate::server::{Identity, OAuthProvider};
+
+/// The token response from the `Server`, along with
+/// what the proxy knows about the login.
+///
+/// Dereferences to the `AccessToken`, so handlers written
+/// against the bare token keep working.
+#[derive(Clone, Debug)]
+pub struct TokenInfo {
+    pub access_token: AccessToken,
+    pub token_type: BasicTokenType,
+
+    /// Lifetime of the access token, if the `Server` said.
+    pub expires_in: Option<Duration>,
+
+    pub refresh_token: Option<RefreshToken>,
+
+    /// Scopes the `User` actually granted, if the `Server` said.
+    /// These may be fewer than `requested_scopes`.
+    pub scopes: Option<Vec<Scope>>,
+
+    /// Scopes the proxy asked for.
+    pub requested_scopes: Vec<Scope>,
+
+    /// Name of the provider which issued the token, as configured
+    /// under `providers`, or `None` for the provider given at the
+    /// top level of the configuration.
+    pub provider: Option<String>,
+
+    /// Who the `User` is, according to the provider.
+    /// `None` if the provider has no userinfo endpoint.
+    pub identity: Option<Identity>,
+
+    /// Claims of the validated `id_token`, for OpenID Connect providers.
+    pub id_token_claims: Option<Claims>,
+
+    /// Port of the client's local listener, for `Delivery::Listener`.
+    pub client_port: Option<u16>,
+
+    /// When the login was started.
+    pub requested_at: SystemTime,
+}
+
+impl TokenInfo {
+    pub(crate) fn new(
+        token: BasicTokenResponse,
+        provider: &dyn OAuthProvider,
+        name: Option<String>,
+        requested_scopes: Vec<Scope>,
+        client_port: Option<u16>,
+        requested_at: SystemTime,
+    ) -> Self {
+        // Some providers (e.g. Github) separate granted scopes with
+        // something other than spaces, so they arrive as a single `Scope`.
+        let separator = provider.scope_separator();
+        let scopes = token.scopes().map(|scopes| {
+            scopes.iter()
+                .flat_map(|scope| scope.split(separator).map(str::trim).collect::<Vec<_>>())
+                .filter(|scope| !scope.is_empty())
+                .map(|scope| Scope::new(scope.to_string()))
+                .collect()
+        });
+        TokenInfo {
+            access_token: token.access_token().clone(),
+            token_type: token.token_type().clone(),
+            expires_in: token.expires_in(),
+            refresh_token: token.refresh_token().cloned(),
+            scopes,
+            requested_scopes,
+            provider: name,
+            identity: None,
+            id_token_claims: None,
+            client_port,
+            requested_at,
+        }
+    }
+
+    /// Whether the `User` granted `scope`. If the `Server` did not
+    /// say which scopes were granted, assumes all requested scopes were.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.granted_scopes().iter().any(|s| s.as_str() == scope)
+    }
+
+    /// The scopes the `User` granted, on the same assumption as `has_scope`.
+    pub fn granted_scopes(&self) -> &[Scope] {
+        self.scopes.as_ref().unwrap_or(&self.requested_scopes)
+    }
+}
+
+impl Deref for TokenInfo {
+    type Target = AccessToken;
+    fn deref(&self) -> &AccessToken {
+        &self.access_token
+    }
+}
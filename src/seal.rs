@@ -0,0 +1,173 @@
+//! Sealing the `FinResponse` to the client's ephemeral key.
+//!
+//! The client sends an X25519 public key with `GenParams`. The proxy
+//! generates its own ephemeral key, and derives a ChaCha20-Poly1305 key
+//! from the shared secret using HKDF-SHA256, binding in both public keys.
+//! The CSRF `state` is authenticated along with the ciphertext, so a
+//! sealed response cannot be replayed into another login.
+//!
+//! Each derived key seals exactly one message, so a fixed nonce is safe.
+
+use failure::{bail, format_err, Error};
+use ring::{aead, agreement, digest, hkdf, hmac, rand};
+use untrusted::Input;
+
+/// HKDF `info`, followed by the proxy's and the client's public keys.
+const HKDF_INFO: &[u8] = b"olaf2 sealed FinResponse v1";
+
+const NONCE: [u8; 12] = [0; 12];
+
+/// Length of an X25519 public key.
+const PUBLIC_KEY_LEN: usize = 32;
+
+/// The client's ephemeral key for one login.
+///
+/// Only the holder of the key can open the response, and only once.
+pub(crate) struct ClientKey {
+    private: agreement::EphemeralPrivateKey,
+    public: Vec<u8>,
+}
+
+impl ClientKey {
+    pub fn generate() -> Result<Self, Error> {
+        let (private, public) = generate()?;
+        Ok(ClientKey { private, public })
+    }
+
+    /// The public key, as sent in `GenParams::client_key`.
+    pub fn public_key(&self) -> String {
+        encode(&self.public)
+    }
+
+    /// Open a response produced by `seal` for the login with `state`.
+    pub fn open(self, sealed: &str, state: &str) -> Result<Vec<u8>, Error> {
+        let mut parts = sealed.splitn(2, '.');
+        let (sender, mut in_out) = match (parts.next(), parts.next()) {
+            (Some(sender), Some(ciphertext)) => (decode(sender)?, decode(ciphertext)?),
+            _ => bail!("malformed sealed response"),
+        };
+        let recipient = self.public;
+        let key = agreement::agree_ephemeral(
+            self.private,
+            &agreement::X25519,
+            Input::from(&sender),
+            format_err!("key agreement failed"),
+            |shared| Ok(derive_key(shared, &sender, &recipient)),
+        )?;
+        let key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, &key)
+            .map_err(|_| format_err!("invalid opening key"))?;
+        let len = aead::open_in_place(&key, &NONCE, state.as_bytes(), 0, &mut in_out)
+            .map_err(|_| format_err!("could not open sealed response"))?
+            .len();
+        in_out.truncate(len);
+        Ok(in_out)
+    }
+}
+
+/// Check `client_key` is a well-formed public key.
+pub(crate) fn check_public_key(client_key: &str) -> Result<(), Error> {
+    match decode(client_key) {
+        Ok(ref key) if key.len() == PUBLIC_KEY_LEN => Ok(()),
+        _ => bail!("malformed client_key"),
+    }
+}
+
+/// Seal `plaintext` to `client_key`, for the login with `state`.
+pub(crate) fn seal(client_key: &str, state: &str, plaintext: &[u8]) -> Result<String, Error> {
+    let recipient = decode(client_key)?;
+    let (private, sender) = generate()?;
+    let key = agreement::agree_ephemeral(
+        private,
+        &agreement::X25519,
+        Input::from(&recipient),
+        format_err!("invalid client_key"),
+        |shared| Ok(derive_key(shared, &sender, &recipient)),
+    )?;
+    let key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &key)
+        .map_err(|_| format_err!("invalid sealing key"))?;
+    let tag_len = aead::CHACHA20_POLY1305.tag_len();
+    let mut in_out = plaintext.to_vec();
+    in_out.resize(plaintext.len() + tag_len, 0);
+    let len = aead::seal_in_place(&key, &NONCE, state.as_bytes(), &mut in_out, tag_len)
+        .map_err(|_| format_err!("could not seal response"))?;
+    in_out.truncate(len);
+    Ok(format!("{}.{}", encode(&sender), encode(&in_out)))
+}
+
+fn generate() -> Result<(agreement::EphemeralPrivateKey, Vec<u8>), Error> {
+    let rng = rand::SystemRandom::new();
+    let private = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng)
+        .map_err(|_| format_err!("could not generate key"))?;
+    let mut public = vec![0; private.public_key_len()];
+    private.compute_public_key(&mut public)
+        .map_err(|_| format_err!("could not compute public key"))?;
+    Ok((private, public))
+}
+
+fn derive_key(shared: &[u8], sender: &[u8], recipient: &[u8]) -> [u8; 32] {
+    let salt = hmac::SigningKey::new(&digest::SHA256, &[]);
+    let mut info = HKDF_INFO.to_vec();
+    info.extend_from_slice(sender);
+    info.extend_from_slice(recipient);
+    let mut key = [0; 32];
+    hkdf::extract_and_expand(&salt, shared, &info, &mut key);
+    key
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(s: &str) -> Result<Vec<u8>, Error> {
+    Ok(base64::decode_config(s, base64::URL_SAFE_NO_PAD)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATE: &str = "state";
+
+    #[test]
+    fn round_trip() {
+        let key = ClientKey::generate().unwrap();
+        let sealed = seal(&key.public_key(), STATE, b"secret").unwrap();
+        assert_eq!(key.open(&sealed, STATE).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn other_key_cannot_open() {
+        let key = ClientKey::generate().unwrap();
+        let other = ClientKey::generate().unwrap();
+        let sealed = seal(&key.public_key(), STATE, b"secret").unwrap();
+        assert!(other.open(&sealed, STATE).is_err());
+    }
+
+    #[test]
+    fn other_state_cannot_open() {
+        let key = ClientKey::generate().unwrap();
+        let sealed = seal(&key.public_key(), STATE, b"secret").unwrap();
+        assert!(key.open(&sealed, "other-state").is_err());
+    }
+
+    #[test]
+    fn tampered_ciphertext_cannot_open() {
+        let key = ClientKey::generate().unwrap();
+        let sealed = seal(&key.public_key(), STATE, b"secret").unwrap();
+        let mut parts = sealed.splitn(2, '.');
+        let (sender, ciphertext) = (parts.next().unwrap(), parts.next().unwrap());
+        let mut ciphertext = decode(ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        let tampered = format!("{}.{}", sender, encode(&ciphertext));
+        assert!(key.open(&tampered, STATE).is_err());
+    }
+
+    #[test]
+    fn public_key_length_is_checked() {
+        let key = ClientKey::generate().unwrap();
+        assert!(check_public_key(&key.public_key()).is_ok());
+        assert!(check_public_key(&encode(&[7; PUBLIC_KEY_LEN - 1])).is_err());
+        assert!(check_public_key(&encode(&[7; PUBLIC_KEY_LEN + 1])).is_err());
+        assert!(check_public_key("not base64!").is_err());
+    }
+}
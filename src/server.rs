@@ -1,17 +1,95 @@
 //! Paramterization of the OAuth 2.0 providers
+//!
+//! Each provider knows its endpoints, the scopes to request by default,
+//! how to recover the `User`'s `Identity`, and any quirks of its token
+//! endpoint. The proxy configuration (`proxy_config.toml`) names one of
+//! them as its `oauth_provider`.
+//!
+//! Providers without settings are named directly:
+//!
+//! ```toml
+//! oauth_provider = "Github"  # or "Google", or "Bitbucket"
+//! ```
+//!
+//! The others are given as a table:
+//!
+//! ```toml
+//! [oauth_provider.GithubEnterprise]
+//! host = "github.example.com"
+//! ```
+//!
+//! ```toml
+//! [oauth_provider.GitLab]
+//! base_url = "https://gitlab.example.com/"  # defaults to https://gitlab.com/
+//! ```
+//!
+//! ```toml
+//! [oauth_provider.AzureAd]
+//! tenant = "contoso.onmicrosoft.com"  # defaults to "common"
+//! ```
+//!
+//! ```toml
+//! [oauth_provider.Oidc]
+//! issuer = "https://accounts.example.com"
+//! ```
+//!
+//! ```toml
+//! [oauth_provider.Custom]
+//! auth_url = "https://auth.example.com/authorize"
+//! token_url = "https://auth.example.com/token"
+//! userinfo_url = "https://auth.example.com/userinfo"
+//! ```
+//!
+//! If `scopes` is left out of the configuration, the
+//! provider's default scopes are requested.
+//!
+//! Other providers can be added by implementing `OAuthProvider`, and
+//! registering them with `register`. They are then named in the
+//! configuration, e.g. `oauth_provider = "my-sso"`.
 
+use failure::Error;
 use serde_derive::Deserialize;
 use oauth2::prelude::*;
-use oauth2::{AuthUrl, TokenUrl};
+use oauth2::{AccessToken, AuthType, AuthUrl, Scope, TokenUrl};
 use url::Url;
 
 use crate::util::*;
 
-/// The `Provider` enum captures the different OAuth 2.0
+mod identity;
+pub mod oidc;
+mod provider;
+
+pub use self::identity::Identity;
+pub use self::provider::{lookup, register, OAuthProvider, ProviderConfig};
+
+/// The `Provider` enum captures the built-in OAuth 2.0
 /// authentication providers.
 #[derive(Clone, Debug, Deserialize)]
 pub enum Provider {
     Github,
+    /// A Github Enterprise Server installation.
+    GithubEnterprise {
+        /// Host name, e.g. `github.example.com`.
+        host: String,
+    },
+    /// gitlab.com, or a self-hosted GitLab.
+    GitLab {
+        #[serde(default="default_gitlab_url", with="url_serde")]
+        base_url: Url,
+    },
+    Google,
+    Bitbucket,
+    /// Microsoft identity platform (v2.0 endpoints).
+    ///
+    /// Its userinfo endpoint does not send `email_verified`, so the
+    /// `Identity` has no email, and `allowed_email_domains` cannot
+    /// be used.
+    AzureAd {
+        /// Tenant ID or domain, or `common`, `organizations`
+        /// or `consumers`.
+        #[serde(default="default_azure_tenant")]
+        tenant: String,
+    },
     Custom {
         /// URL to authorize the OAuth2.0 request
         #[serde(with="serde_newtype_url")]
@@ -21,17 +99,314 @@ pub enum Provider {
         /// authorization.
         #[serde(with="serde_newtype_url")]
         token_url: TokenUrl,
-    }
+
+        /// URL to start a device authorization grant, if supported.
+        #[serde(default, with="url_serde")]
+        device_auth_url: Option<Url>,
+
+        /// OpenID Connect style userinfo endpoint, used to
+        /// recover the `User`'s `Identity`.
+        #[serde(default, with="url_serde")]
+        userinfo_url: Option<Url>,
+    },
+    /// An OpenID Connect provider, configured by discovery.
+    ///
+    /// The `id_token` is validated at each login, which gives
+    /// real authentication of the `User`.
+    Oidc {
+        #[serde(with="url_serde")]
+        issuer: Url,
+    },
+}
+
+fn default_gitlab_url() -> Url {
+    Url::parse("https://gitlab.com/").unwrap()
+}
+
+fn default_azure_tenant() -> String {
+    "common".to_string()
+}
+
+/// Parse a URL built from (validated) configuration values.
+fn url(url: &str) -> Url {
+    Url::parse(url).unwrap_or_else(|e| panic!("invalid provider URL {}: {}", url, e))
 }
 
 impl Provider {
+    /// The authorization and token endpoints, see `OAuthProvider::urls`.
     pub fn into_urls(self) -> (AuthUrl, TokenUrl) {
+        self.urls()
+    }
+
+    /// Endpoint for recovering the `User`'s identity, if any.
+    pub fn userinfo_url(&self) -> Option<Url> {
+        match self {
+            Provider::Github => Some(url("https://api.github.com/user")),
+            Provider::GithubEnterprise { host } => Some(url(&format!("https://{}/api/v3/user", host))),
+            Provider::GitLab { base_url } => Some(gitlab_url(base_url, "oauth/userinfo")),
+            Provider::Google => Some(url("https://openidconnect.googleapis.com/v1/userinfo")),
+            Provider::Bitbucket => Some(url("https://api.bitbucket.org/2.0/user")),
+            Provider::AzureAd { .. } => Some(url("https://graph.microsoft.com/oidc/userinfo")),
+            Provider::Custom { userinfo_url, .. } => userinfo_url.clone(),
+            Provider::Oidc { issuer } => oidc::discover(issuer).ok()
+                .and_then(|d| d.userinfo_endpoint.clone()),
+        }
+    }
+}
+
+impl OAuthProvider for Provider {
+    fn name(&self) -> &str {
         match self {
+            Provider::Github => "Github",
+            Provider::GithubEnterprise { .. } => "GithubEnterprise",
+            Provider::GitLab { .. } => "GitLab",
+            Provider::Google => "Google",
+            Provider::Bitbucket => "Bitbucket",
+            Provider::AzureAd { .. } => "AzureAd",
+            Provider::Custom { .. } => "Custom",
+            Provider::Oidc { .. } => "Oidc",
+        }
+    }
+
+    fn urls(&self) -> (AuthUrl, TokenUrl) {
+        let (auth_url, token_url) = match self {
             Provider::Github => (
-                AuthUrl::new(Url::parse("https://github.com/login/oauth/authorize").unwrap()),
-                TokenUrl::new(Url::parse("https://github.com/login/oauth/access_token").unwrap())
+                url("https://github.com/login/oauth/authorize"),
+                url("https://github.com/login/oauth/access_token"),
+            ),
+            Provider::GithubEnterprise { host } => (
+                url(&format!("https://{}/login/oauth/authorize", host)),
+                url(&format!("https://{}/login/oauth/access_token", host)),
+            ),
+            Provider::GitLab { base_url } => (
+                gitlab_url(base_url, "oauth/authorize"),
+                gitlab_url(base_url, "oauth/token"),
+            ),
+            Provider::Google => (
+                url("https://accounts.google.com/o/oauth2/v2/auth"),
+                url("https://oauth2.googleapis.com/token"),
+            ),
+            Provider::Bitbucket => (
+                url("https://bitbucket.org/site/oauth2/authorize"),
+                url("https://bitbucket.org/site/oauth2/access_token"),
             ),
-            Provider::Custom { auth_url, token_url } => (auth_url, token_url),
+            Provider::AzureAd { tenant } => (
+                url(&format!("https://login.microsoftonline.com/{}/oauth2/v2.0/authorize", tenant)),
+                url(&format!("https://login.microsoftonline.com/{}/oauth2/v2.0/token", tenant)),
+            ),
+            Provider::Custom { auth_url, token_url, .. } => return (auth_url.clone(), token_url.clone()),
+            // Only needed when starting the proxy, so fail loudly.
+            Provider::Oidc { issuer } => {
+                let discovery = oidc::discover(issuer).unwrap_or_else(|e| {
+                    panic!("could not discover OpenID configuration for {}: {}", issuer, e)
+                });
+                (discovery.authorization_endpoint.clone(), discovery.token_endpoint.clone())
+            },
+        };
+        (AuthUrl::new(auth_url), TokenUrl::new(token_url))
+    }
+
+    /// Enough to recover the `User`'s `Identity`.
+    fn default_scopes(&self) -> Vec<Scope> {
+        let scopes: &[&str] = match self {
+            Provider::Github | Provider::GithubEnterprise { .. } => &["read:user", "user:email"],
+            Provider::GitLab { .. } => &["openid", "profile", "email"],
+            Provider::Google => &["openid", "profile", "email"],
+            Provider::Bitbucket => &["account", "email"],
+            // offline_access is needed for a refresh token.
+            Provider::AzureAd { .. } => &["openid", "profile", "email", "offline_access"],
+            Provider::Custom { .. } => &[],
+            Provider::Oidc { .. } => &["openid", "profile", "email"],
+        };
+        scopes.iter().map(|s| Scope::new(s.to_string())).collect()
+    }
+
+    fn authorize_params(&self) -> Vec<(&'static str, String)> {
+        match self {
+            // Google only issues a refresh token for offline access,
+            // and only on first consent unless prompted again.
+            Provider::Google => vec![
+                ("access_type", "offline".to_string()),
+                ("prompt", "consent".to_string()),
+            ],
+            _ => Vec::new(),
+        }
+    }
+
+    fn auth_type(&self) -> AuthType {
+        match self {
+            // Only accepts HTTP Basic authentication.
+            Provider::Bitbucket => AuthType::BasicAuth,
+            _ => AuthType::RequestBody,
         }
     }
+
+    fn scope_separator(&self) -> &str {
+        match self {
+            // e.g. "read:org,user:email"
+            Provider::Github | Provider::GithubEnterprise { .. } => ",",
+            _ => " ",
+        }
+    }
+
+    fn device_auth_url(&self) -> Option<Url> {
+        match self {
+            Provider::Github => Some(url("https://github.com/login/device/code")),
+            Provider::GithubEnterprise { host } => Some(url(&format!("https://{}/login/device/code", host))),
+            Provider::GitLab { base_url } => Some(gitlab_url(base_url, "oauth/authorize_device")),
+            Provider::Google => Some(url("https://oauth2.googleapis.com/device/code")),
+            Provider::Bitbucket => None,
+            Provider::AzureAd { tenant } => Some(url(&format!(
+                "https://login.microsoftonline.com/{}/oauth2/v2.0/devicecode", tenant
+            ))),
+            Provider::Custom { device_auth_url, .. } => device_auth_url.clone(),
+            Provider::Oidc { issuer } => oidc::discover(issuer).ok()
+                .and_then(|d| d.device_authorization_endpoint.clone()),
+        }
+    }
+
+    fn issuer(&self) -> Option<Url> {
+        match self {
+            Provider::Oidc { issuer } => Some(issuer.clone()),
+            _ => None,
+        }
+    }
+
+    fn identity(&self, http: &reqwest::Client, token: &AccessToken) -> Result<Option<Identity>, Error> {
+        match self.api_url() {
+            Some(api_url) => self.identity_at(http, &api_url, token),
+            None => match self.userinfo_url() {
+                Some(url) => identity::userinfo(http, &url, token).map(Some),
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+impl Provider {
+    /// Root of the REST API used to recover the `User`'s identity,
+    /// for providers without a userinfo endpoint.
+    pub fn api_url(&self) -> Option<Url> {
+        match self {
+            Provider::Github => Some(url("https://api.github.com/")),
+            Provider::GithubEnterprise { host } => Some(url(&format!("https://{}/api/v3/", host))),
+            Provider::Bitbucket => Some(url("https://api.bitbucket.org/2.0/")),
+            _ => None,
+        }
+    }
+
+    /// Recover the `User`'s identity from the REST API rooted at
+    /// `api_url`, instead of the provider's own.
+    ///
+    /// Returns `None` for providers without such an API.
+    pub fn identity_at(&self, http: &reqwest::Client, api_url: &Url, token: &AccessToken)
+        -> Result<Option<Identity>, Error>
+    {
+        match self {
+            Provider::Github | Provider::GithubEnterprise { .. } => {
+                identity::github(http, api_url, token).map(Some)
+            },
+            Provider::Bitbucket => identity::bitbucket(http, api_url, token).map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// `path` on a GitLab instance, which may be installed under a sub-path.
+fn gitlab_url(base_url: &Url, path: &str) -> Url {
+    let mut base = base_url.clone();
+    if !base.path().ends_with('/') {
+        let path = format!("{}/", base.path());
+        base.set_path(&path);
+    }
+    base.join(path).unwrap_or_else(|e| panic!("invalid GitLab URL {}: {}", base_url, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::MockServer;
+
+    fn token() -> AccessToken {
+        AccessToken::new("access-token".to_string())
+    }
+
+    #[test]
+    fn github_identity() {
+        let server = MockServer::start(&[
+            ("/user", r#"{"id": 1, "login": "octocat", "name": "The Octocat", "email": null}"#),
+            ("/user/emails", r#"[
+                {"email": "old@example.com", "primary": false, "verified": true},
+                {"email": "octocat@example.com", "primary": true, "verified": true}
+            ]"#),
+            ("/user/orgs", r#"[{"login": "octo-org"}]"#),
+            ("/user/teams", r#"[{"slug": "admins", "organization": {"login": "octo-org"}}]"#),
+        ]);
+
+        let identity = Provider::Github
+            .identity_at(&reqwest::Client::new(), &server.url("/"), &token())
+            .unwrap();
+        assert_eq!(identity, Some(Identity {
+            id: "1".to_string(),
+            login: Some("octocat".to_string()),
+            email: Some("octocat@example.com".to_string()),
+            name: Some("The Octocat".to_string()),
+            groups: vec!["octo-org".to_string(), "octo-org/admins".to_string()],
+        }));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        for request in requests {
+            assert_eq!(request.method, "GET");
+            assert_eq!(request.headers["authorization"], "Bearer access-token");
+        }
+    }
+
+    #[test]
+    fn custom_identity() {
+        let server = MockServer::start(&[
+            ("/userinfo", r#"{
+                "sub": "42",
+                "preferred_username": "jdoe",
+                "email": "jdoe@example.com",
+                "email_verified": true,
+                "name": "Jane Doe",
+                "groups": ["staff"]
+            }"#),
+        ]);
+        let provider = Provider::Custom {
+            auth_url: AuthUrl::new(server.url("authorize")),
+            token_url: TokenUrl::new(server.url("token")),
+            device_auth_url: None,
+            userinfo_url: Some(server.url("userinfo")),
+        };
+
+        let identity = provider.identity(&reqwest::Client::new(), &token()).unwrap();
+        assert_eq!(identity, Some(Identity {
+            id: "42".to_string(),
+            login: Some("jdoe".to_string()),
+            email: Some("jdoe@example.com".to_string()),
+            name: Some("Jane Doe".to_string()),
+            groups: vec!["staff".to_string()],
+        }));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/userinfo");
+        assert_eq!(requests[0].headers["authorization"], "Bearer access-token");
+    }
+
+    #[test]
+    fn custom_without_userinfo_has_no_identity() {
+        let server = MockServer::start(&[]);
+        let provider = Provider::Custom {
+            auth_url: AuthUrl::new(server.url("authorize")),
+            token_url: TokenUrl::new(server.url("token")),
+            device_auth_url: None,
+            userinfo_url: None,
+        };
+
+        assert_eq!(provider.identity(&reqwest::Client::new(), &token()).unwrap(), None);
+        assert!(server.requests().is_empty());
+    }
 }
@@ -0,0 +1,228 @@
+//! Recovering the `User`'s identity from the provider.
+//!
+//! This is what makes OAuth 2.0 usable for authn: with the access
+//! token, the proxy asks the `Server` who the `User` is.
+
+use failure::{format_err, Error};
+use log::*;
+use oauth2::prelude::*;
+use oauth2::AccessToken;
+use reqwest::header::{ACCEPT, USER_AGENT};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
+
+/// The `User`'s identity, normalized across providers.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Identity {
+    /// Stable identifier for the `User` at the provider.
+    pub id: String,
+
+    /// Username, if the provider has them.
+    pub login: Option<String>,
+
+    /// Primary, verified email address, if available.
+    pub email: Option<String>,
+
+    /// Display name.
+    pub name: Option<String>,
+
+    /// Organization and group memberships.
+    /// For Github, organizations are listed by login,
+    /// and teams as `org/team-slug`.
+    pub groups: Vec<String>,
+}
+
+/// Sent with each request, since the Github API rejects requests without.
+const OLAF2_USER_AGENT: &str = concat!("olaf2/", env!("CARGO_PKG_VERSION"));
+
+fn get<T: DeserializeOwned>(http: &reqwest::Client, url: Url, token: &AccessToken) -> Result<T, Error> {
+    let mut resp = http.get(url.clone())
+        .bearer_auth(token.secret())
+        .header(ACCEPT, "application/json")
+        .header(USER_AGENT, OLAF2_USER_AGENT)
+        .send()?;
+    if !resp.status().is_success() {
+        return Err(format_err!("{} returned {}", url, resp.status()));
+    }
+    Ok(resp.json()?)
+}
+
+#[derive(Deserialize)]
+struct GithubUser {
+    id: u64,
+    login: String,
+    name: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GithubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+#[derive(Deserialize)]
+struct GithubOrg {
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct GithubTeam {
+    slug: String,
+    organization: GithubOrg,
+}
+
+/// Fetch the identity from a Github-style API rooted at `api_url`.
+///
+/// Only the user itself is required. Emails, organizations and teams
+/// need extra scopes (`user:email`, `read:org`), and are skipped if
+/// they were not granted.
+pub(crate) fn github(http: &reqwest::Client, api_url: &Url, token: &AccessToken) -> Result<Identity, Error> {
+    let user: GithubUser = get(http, api_url.join("user")?, token)?;
+    let email = match user.email {
+        Some(email) => Some(email),
+        None => get::<Vec<GithubEmail>>(http, api_url.join("user/emails")?, token)
+            .map_err(|e| debug!("Could not list emails: {}", e))
+            .ok()
+            .and_then(|emails| emails.into_iter().find(|e| e.primary && e.verified))
+            .map(|e| e.email),
+    };
+    let mut groups = Vec::new();
+    match get::<Vec<GithubOrg>>(http, api_url.join("user/orgs")?, token) {
+        Ok(orgs) => groups.extend(orgs.into_iter().map(|org| org.login)),
+        Err(e) => debug!("Could not list organizations: {}", e),
+    }
+    match get::<Vec<GithubTeam>>(http, api_url.join("user/teams")?, token) {
+        Ok(teams) => groups.extend(teams.into_iter()
+            .map(|team| format!("{}/{}", team.organization.login, team.slug))),
+        Err(e) => debug!("Could not list teams: {}", e),
+    }
+    Ok(Identity {
+        id: user.id.to_string(),
+        login: Some(user.login),
+        email,
+        name: user.name,
+        groups,
+    })
+}
+
+#[derive(Deserialize)]
+struct BitbucketUser {
+    uuid: String,
+    nickname: Option<String>,
+    display_name: Option<String>,
+}
+
+/// One page of a Bitbucket list response.
+#[derive(Deserialize)]
+struct BitbucketPage<T> {
+    values: Vec<T>,
+}
+
+#[derive(Deserialize)]
+struct BitbucketEmail {
+    email: String,
+    is_primary: bool,
+    is_confirmed: bool,
+}
+
+#[derive(Deserialize)]
+struct BitbucketWorkspace {
+    slug: String,
+}
+
+#[derive(Deserialize)]
+struct BitbucketPermission {
+    workspace: BitbucketWorkspace,
+}
+
+/// Fetch the identity from the Bitbucket API rooted at `api_url`.
+///
+/// Workspaces the `User` belongs to are listed as groups, by slug.
+pub(crate) fn bitbucket(http: &reqwest::Client, api_url: &Url, token: &AccessToken) -> Result<Identity, Error> {
+    let user: BitbucketUser = get(http, api_url.join("user")?, token)?;
+    let email = get::<BitbucketPage<BitbucketEmail>>(http, api_url.join("user/emails")?, token)
+        .map_err(|e| debug!("Could not list emails: {}", e))
+        .ok()
+        .and_then(|page| page.values.into_iter().find(|e| e.is_primary && e.is_confirmed))
+        .map(|e| e.email);
+    let groups = match get::<BitbucketPage<BitbucketPermission>>(
+        http, api_url.join("user/permissions/workspaces")?, token
+    ) {
+        Ok(page) => page.values.into_iter().map(|p| p.workspace.slug).collect(),
+        Err(e) => {
+            debug!("Could not list workspaces: {}", e);
+            Vec::new()
+        },
+    };
+    Ok(Identity {
+        id: user.uuid,
+        login: user.nickname,
+        email,
+        name: user.display_name,
+        groups,
+    })
+}
+
+/// Fetch the identity from an OpenID Connect style userinfo endpoint.
+///
+/// Understands the standard claims (`sub`, `preferred_username`,
+/// `email`, `name`), along with common alternatives (`id`, `login`,
+/// `username`) and a `groups` claim.
+pub(crate) fn userinfo(http: &reqwest::Client, url: &Url, token: &AccessToken) -> Result<Identity, Error> {
+    let claims: Value = get(http, url.clone(), token)?;
+    Identity::from_claims(&claims)
+}
+
+impl Identity {
+    /// Normalize a set of claims, as returned by a userinfo
+    /// endpoint or found in an `id_token`.
+    ///
+    /// The `email` is left out unless `email_verified` is `true`.
+    pub fn from_claims(claims: &Value) -> Result<Identity, Error> {
+        let string = |keys: &[&str]| keys.iter()
+            .filter_map(|key| match claims.get(key) {
+                Some(Value::String(s)) => Some(s.clone()),
+                Some(Value::Number(n)) => Some(n.to_string()),
+                _ => None,
+            })
+            .next();
+        let id = string(&["sub", "id"])
+            .ok_or_else(|| format_err!("claims have no `sub` or `id`"))?;
+        // Unverified addresses must not be trusted for authn, so the
+        // email is only kept if the provider vouches for it.
+        let verified = claims.get("email_verified").and_then(Value::as_bool).unwrap_or(false);
+        let groups = match claims.get("groups") {
+            Some(Value::Array(groups)) => groups.iter()
+                .filter_map(|g| g.as_str().map(str::to_string))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok(Identity {
+            id,
+            login: string(&["preferred_username", "login", "username"]),
+            email: string(&["email"]).filter(|_| verified),
+            name: string(&["name"]),
+            groups,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn email_needs_to_be_verified() {
+        let identity = |claims| Identity::from_claims(&claims).unwrap().email;
+        let email = Some("user@example.com".to_string());
+        assert_eq!(identity(json!({"sub": "1", "email": "user@example.com"})), None);
+        assert_eq!(identity(json!({"sub": "1", "email": "user@example.com", "email_verified": false})), None);
+        assert_eq!(identity(json!({"sub": "1", "email": "user@example.com", "email_verified": true})), email);
+    }
+}
@@ -0,0 +1,458 @@
+//! OpenID Connect: discovery, and validation of `id_token`s.
+//!
+//! Provider metadata is fetched from the issuer's
+//! `/.well-known/openid-configuration` and cached, as are its signing
+//! keys. The keys are fetched again when a token names an unknown key,
+//! so the provider can rotate them.
+
+use failure::{bail, format_err, Error};
+use lazy_static::lazy_static;
+use log::*;
+use reqwest::header::ACCEPT;
+use serde_derive::Deserialize;
+use serde_json::{Map, Value};
+use url::Url;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The claims of a validated `id_token`.
+pub type Claims = Map<String, Value>;
+
+/// Allowance (in seconds) for clock skew when checking expiry.
+const CLOCK_SKEW_SECS: u64 = 60;
+
+/// Provider metadata, see
+/// [OpenID Connect Discovery](https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderMetadata).
+#[derive(Clone, Debug, Deserialize)]
+pub struct Discovery {
+    pub issuer: String,
+    #[serde(with="url_serde")]
+    pub authorization_endpoint: Url,
+    #[serde(with="url_serde")]
+    pub token_endpoint: Url,
+    #[serde(default, with="url_serde")]
+    pub userinfo_endpoint: Option<Url>,
+    #[serde(with="url_serde")]
+    pub jwks_uri: Url,
+    #[serde(default, with="url_serde")]
+    pub device_authorization_endpoint: Option<Url>,
+}
+
+/// A public key from the provider's JWKS.
+#[derive(Clone, Debug, Deserialize)]
+struct Jwk {
+    kid: Option<String>,
+    kty: String,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+lazy_static! {
+    static ref DISCOVERY: Mutex<HashMap<String, Arc<Discovery>>> = Mutex::new(HashMap::new());
+    static ref JWKS: Mutex<HashMap<String, Arc<Vec<Jwk>>>> = Mutex::new(HashMap::new());
+}
+
+/// Fetch (or return the cached) metadata for `issuer`.
+pub fn discover(issuer: &Url) -> Result<Arc<Discovery>, Error> {
+    if let Some(discovery) = DISCOVERY.lock().unwrap().get(issuer.as_str()) {
+        return Ok(discovery.clone());
+    }
+    // Issuers may have a path, which the well-known suffix is appended to.
+    let url = Url::parse(&format!(
+        "{}/.well-known/openid-configuration",
+        issuer.as_str().trim_end_matches('/')
+    ))?;
+    let discovery: Discovery = get_json(&url)?;
+    if discovery.issuer.trim_end_matches('/') != issuer.as_str().trim_end_matches('/') {
+        bail!("discovery document for {} names issuer {}", issuer, discovery.issuer);
+    }
+    let discovery = Arc::new(discovery);
+    DISCOVERY.lock().unwrap().insert(issuer.to_string(), discovery.clone());
+    Ok(discovery)
+}
+
+fn get_json<T: serde::de::DeserializeOwned>(url: &Url) -> Result<T, Error> {
+    let mut resp = reqwest::Client::new()
+        .get(url.clone())
+        .header(ACCEPT, "application/json")
+        .send()?;
+    if !resp.status().is_success() {
+        bail!("{} returned {}", url, resp.status());
+    }
+    Ok(resp.json()?)
+}
+
+/// The signing keys at `jwks_uri`, fetching them if not
+/// cached or if `refresh` is set.
+fn keys(jwks_uri: &Url, refresh: bool) -> Result<Arc<Vec<Jwk>>, Error> {
+    if !refresh {
+        if let Some(keys) = JWKS.lock().unwrap().get(jwks_uri.as_str()) {
+            return Ok(keys.clone());
+        }
+    }
+    debug!("Fetching signing keys from {}", jwks_uri);
+    let set: JwkSet = get_json(jwks_uri)?;
+    let keys = Arc::new(set.keys);
+    JWKS.lock().unwrap().insert(jwks_uri.to_string(), keys.clone());
+    Ok(keys)
+}
+
+fn find_key(keys: &[Jwk], kid: Option<&str>) -> Option<Jwk> {
+    let mut rsa = keys.iter().filter(|k| k.kty == "RSA");
+    match kid {
+        Some(kid) => rsa.find(|k| k.kid.as_ref().map(String::as_str) == Some(kid)).cloned(),
+        // Without a `kid`, the key must be unambiguous.
+        None => {
+            let key = rsa.next().cloned();
+            if rsa.next().is_some() { None } else { key }
+        },
+    }
+}
+
+fn decode(part: &str) -> Result<Vec<u8>, Error> {
+    Ok(base64::decode_config(part, base64::URL_SAFE_NO_PAD)?)
+}
+
+/// Validate `id_token` as issued by the provider described by
+/// `discovery` to `client_id`, for the login with `nonce`.
+///
+/// Checks the RS256 signature, `iss`, `aud` (and `azp`), `exp` and `nonce`,
+/// returning the claims. The `nonce` is `None` for grants which cannot
+/// send one, such as the device authorization grant, and then is not
+/// checked.
+pub fn validate_id_token(discovery: &Discovery, id_token: &str, client_id: &str, nonce: Option<&str>)
+    -> Result<Claims, Error>
+{
+    let parts: Vec<&str> = id_token.split('.').collect();
+    if parts.len() != 3 {
+        bail!("id_token is not a signed JWT");
+    }
+    let header: Header = serde_json::from_slice(&decode(parts[0])?)?;
+    if header.alg != "RS256" {
+        bail!("unsupported id_token algorithm {}", header.alg);
+    }
+    let kid = header.kid.as_ref().map(String::as_str);
+    let key = match find_key(&keys(&discovery.jwks_uri, false)?, kid) {
+        Some(key) => key,
+        None => find_key(&keys(&discovery.jwks_uri, true)?, kid)
+            .ok_or_else(|| format_err!("no signing key found for id_token"))?,
+    };
+    let (n, e) = match (key.n, key.e) {
+        (Some(n), Some(e)) => (decode(&n)?, decode(&e)?),
+        _ => bail!("signing key is missing its modulus or exponent"),
+    };
+    let signed = &id_token[..parts[0].len() + 1 + parts[1].len()];
+    ring::signature::verify(
+        &ring::signature::RSA_PKCS1_2048_8192_SHA256,
+        untrusted::Input::from(&rsa_public_key_der(&n, &e)),
+        untrusted::Input::from(signed.as_bytes()),
+        untrusted::Input::from(&decode(parts[2])?),
+    ).map_err(|_| format_err!("id_token signature is invalid"))?;
+
+    let claims: Claims = serde_json::from_slice(&decode(parts[1])?)?;
+    let string = |key: &str| claims.get(key).and_then(Value::as_str);
+
+    if string("iss") != Some(discovery.issuer.as_str()) {
+        bail!("id_token was issued by {:?}, not {}", string("iss"), discovery.issuer);
+    }
+    let audiences: Vec<&str> = match claims.get("aud") {
+        Some(Value::String(aud)) => vec![aud.as_str()],
+        Some(Value::Array(auds)) => auds.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    if !audiences.contains(&client_id) {
+        bail!("id_token is not intended for this client");
+    }
+    if audiences.len() > 1 && string("azp") != Some(client_id) {
+        bail!("id_token has multiple audiences, but was not authorized for this client");
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    match claims.get("exp").and_then(Value::as_u64) {
+        Some(exp) if exp + CLOCK_SKEW_SECS > now => {},
+        Some(_) => bail!("id_token has expired"),
+        None => bail!("id_token has no expiry"),
+    }
+    if let Some(nonce) = nonce {
+        if string("nonce") != Some(nonce) {
+            bail!("id_token nonce does not match the login");
+        }
+    }
+    Ok(claims)
+}
+
+/// DER encoding of an `RSAPublicKey` (RFC 8017 appendix A.1.1),
+/// as expected by `ring`.
+fn rsa_public_key_der(n: &[u8], e: &[u8]) -> Vec<u8> {
+    let mut body = der_integer(n);
+    body.extend(der_integer(e));
+    der_tagged(0x30, &body)
+}
+
+fn der_integer(bytes: &[u8]) -> Vec<u8> {
+    let bytes = match bytes.iter().position(|&b| b != 0) {
+        Some(start) => &bytes[start..],
+        None => &[0][..],
+    };
+    // Integers are signed, so pad if the high bit is set.
+    let mut value = Vec::with_capacity(bytes.len() + 1);
+    if bytes[0] & 0x80 != 0 {
+        value.push(0);
+    }
+    value.extend_from_slice(bytes);
+    der_tagged(0x02, &value)
+}
+
+fn der_tagged(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let len_bytes: Vec<u8> = (0..8).rev()
+            .map(|i| (len >> (i * 8)) as u8)
+            .skip_while(|&b| b == 0)
+            .collect();
+        out.push(0x80 | len_bytes.len() as u8);
+        out.extend(len_bytes);
+    }
+    out.extend_from_slice(value);
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ring::{rand, signature};
+    use serde_json::json;
+
+    use super::*;
+    use crate::mock_server::MockServer;
+
+    /// A fixed 2048-bit test key, as PKCS#8. Its modulus has the high
+    /// bit set (as any 2048-bit modulus does), so needs DER padding.
+    const PKCS8: &str = "MIIEvQIBADANBgkqhkiG9w0BAQEFAASCBKcwggSjAgEAAoIBAQDbRB3jY/WC5KHtQBQt0vlYHXwW\
+    ehNvcrkecUNtUN/pC6/QfURCVbsLHKmwBphunH7/vfOcOc7ZGPONanNS9yPKRag1MQfqJTM/dlsT\
+    PxodnVVBNclqa+PlwcnUX5Uy+zExJHYgEdqKegHYqrKkFwy+E5oFAM6aRtCwcLcLVvAnIRUTYN47\
+    /jyhX2oMYUjDDV3qAygPjYTY6zVgTS4qE/plYCTE41pKuZM/QiQzpAHKstFB2L3bI2cGzyQM5oHH\
+    5ohKRhdb2cCIq7WcxIGK4xLmasxnE+m3HYT+mhjzwcnRsRevyqGc2qOiJf+by0KpaEd7PprJPXY4\
+    U91/6ATr9DMJAgMBAAECggEAGaZ2TfY7mlG0v6m8spT4+7M3TknUTDgCKpWFaxxdlUhQtksb2RwT\
+    rXvE6LAHf6kUZ5veYRWXemjOKmpG43BrN2u+qbpP+rbaBT/gl6J3ePoW0cdrsI9pMaTh6ijkWKUa\
+    CW2tLpiSwEWq7EshFXZnfzTmfP9YgU5sy2HkMpIRC1fpJ3VnmAIPIqjhknK0qwAvSrSLYUFu5fUg\
+    /zqEeBNoJbHd0dlwwFe3CJXWQWgXDR+EiB2dxtdp2jFPwVBLoRcVsKknPNrA2VjhdEtQL/MHJWwA\
+    FvNtjsS6P6A7vbmaZUBmSWV5hw9A9zVCsMuzM5rqQH1LFO9LQnq7qPOay97xoQKBgQD/Bo0ZJ+1U\
+    gOgRqBnwmtW8POtwr+BHkRo1crZjrNLwGXR5S17tzWdgAtR6YjvYqqtcgo3MFkc9CPx4OPjAExlP\
+    oWVo2083jyWvhRdFSZgsl1465Uqm0VKFWuKZnBGtluToadbTdTP9D5E5LzcRDGtdq/thn3OQHroU\
+    ndli9Xsb9wKBgQDcGpaKIQLd0XvsXB83HAjOorl7svMiJIJ/j1pLyGkw/mKxUQb2tuqp1PmtrBBZ\
+    Nm1EOxSb9da1jtuwSAossLQ9p5SkVCGKWf0o0yHSXMnn5YQ8o5gjCSftD+kpCd0SvYJWqNfWSNaO\
+    IxGjwxgF07k5CJ8szQPcEWL+/Mt1MQFo/wKBgBqI8Pd0GxKW0w67Qe+x/Jk4cXrpIdnwdBypkeav\
+    HceTrnXMbNUrcR0hTkBppjZ2E5xtjRl2G/Vy0Oola2R42EJb1bRiVUy/nTk1DOXNsYK6QTi5kNno\
+    n8B2Y2t+PKymLa0tDgg0grNLAl5D1mP0udsmQOK4d4rAV7Y7cloElsmjAoGABH5eeG94GYM/XnH7\
+    CSr/WwijdM0Qfa/1IhmRJF7xFtj6ttgKplrRq6n3dv4+TzP3Qx9Zql14hrhl79Fos8TfySp0tG4r\
+    SSHRM1YsKJlQXfWnn31Pi9eSAxsCDZ5v7wQDdzV3Qp62EqzQnWFMSkIqU+nDJi0BWSrZtI8AsGHR\
+    v40CgYEA4SGICm3beTMxhMBoldQ6fHMd4dzalEp+hXTuuaENJ5gFpDzhAAEkdSpMHK0SgicmYHow\
+    Ao/Uc/V/zqHIEpZV5KPLhojK+45zt7x3d7Xb9YO5nyf+qkOLdad8R/8eWT8350YrbyO1iXm2ljk8\
+    zmB/9NABiGgeW1L4sAUxQ0tpAyQ=";
+
+    /// The modulus and exponent of `PKCS8`, as in a JWK.
+    const N: &str = "20Qd42P1guSh7UAULdL5WB18FnoTb3K5HnFDbVDf6Quv0H1EQlW7CxypsAaYbpx-_73znDnO2Rjz\
+    jWpzUvcjykWoNTEH6iUzP3ZbEz8aHZ1VQTXJamvj5cHJ1F-VMvsxMSR2IBHainoB2KqypBcMvhOa\
+    BQDOmkbQsHC3C1bwJyEVE2DeO_48oV9qDGFIww1d6gMoD42E2Os1YE0uKhP6ZWAkxONaSrmTP0Ik\
+    M6QByrLRQdi92yNnBs8kDOaBx-aISkYXW9nAiKu1nMSBiuMS5mrMZxPptx2E_poY88HJ0bEXr8qh\
+    nNqjoiX_m8tCqWhHez6ayT12OFPdf-gE6_QzCQ";
+    const E: &str = "AQAB";
+
+    const ISSUER: &str = "https://issuer.example.com";
+    const CLIENT_ID: &str = "client";
+    const NONCE: &str = "nonce";
+
+    fn jwks(kids: &[&str]) -> String {
+        json!({
+            "keys": kids.iter()
+                .map(|kid| json!({"kid": kid, "kty": "RSA", "use": "sig", "n": N, "e": E}))
+                .collect::<Vec<_>>(),
+        }).to_string()
+    }
+
+    /// A stub issuer, serving the signing keys `kids`.
+    fn issuer(kids: &[&str]) -> (MockServer, Discovery) {
+        let server = MockServer::start(&[]);
+        server.route("/jwks", &jwks(kids));
+        let discovery = Discovery {
+            issuer: ISSUER.to_string(),
+            authorization_endpoint: server.url("authorize"),
+            token_endpoint: server.url("token"),
+            userinfo_endpoint: None,
+            jwks_uri: server.url("jwks"),
+            device_authorization_endpoint: None,
+        };
+        (server, discovery)
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": ISSUER,
+            "aud": CLIENT_ID,
+            "sub": "user",
+            "nonce": NONCE,
+            "exp": now() + 600,
+        })
+    }
+
+    fn encode(bytes: &[u8]) -> String {
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Sign `claims` with the test key, under `header`.
+    fn sign(header: &Value, claims: &Value) -> String {
+        let signed = format!(
+            "{}.{}",
+            encode(header.to_string().as_bytes()),
+            encode(claims.to_string().as_bytes()),
+        );
+        let pkcs8 = base64::decode(PKCS8).unwrap();
+        let key = signature::RSAKeyPair::from_pkcs8(untrusted::Input::from(&pkcs8)).unwrap();
+        let mut state = signature::RSASigningState::new(Arc::new(key)).unwrap();
+        let mut sig = vec![0; state.key_pair().public_modulus_len()];
+        state.sign(&signature::RSA_PKCS1_SHA256, &rand::SystemRandom::new(), signed.as_bytes(), &mut sig)
+            .unwrap();
+        format!("{}.{}", signed, encode(&sig))
+    }
+
+    fn token(claims: &Value) -> String {
+        sign(&json!({"alg": "RS256", "kid": "test-key"}), claims)
+    }
+
+    fn validate(discovery: &Discovery, id_token: &str) -> Result<Claims, Error> {
+        validate_id_token(discovery, id_token, CLIENT_ID, Some(NONCE))
+    }
+
+    fn assert_rejected(discovery: &Discovery, id_token: &str, reason: &str) {
+        match validate(discovery, id_token) {
+            Err(e) => assert!(e.to_string().contains(reason), "expected {:?}, got {:?}", reason, e.to_string()),
+            Ok(_) => panic!("accepted an id_token which should fail with {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn accepts_valid_token() {
+        let (_server, discovery) = issuer(&["test-key"]);
+        let claims = validate(&discovery, &token(&claims())).unwrap();
+        assert_eq!(claims["sub"], "user");
+        // Without a nonce, e.g. for the device flow.
+        validate_id_token(&discovery, &token(&self::claims()), CLIENT_ID, None).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let (_server, discovery) = issuer(&["test-key"]);
+        let id_token = token(&claims());
+        let (signed, sig) = id_token.split_at(id_token.rfind('.').unwrap() + 1);
+        let mut sig = base64::decode_config(sig, base64::URL_SAFE_NO_PAD).unwrap();
+        sig[0] ^= 1;
+        assert_rejected(&discovery, &format!("{}{}", signed, encode(&sig)), "signature is invalid");
+
+        // Claims swapped in from another token.
+        let mut other = claims();
+        other["sub"] = json!("someone-else");
+        let other = token(&other);
+        let parts: Vec<&str> = id_token.split('.').collect();
+        let forged = format!("{}.{}.{}", parts[0], other.split('.').nth(1).unwrap(), parts[2]);
+        assert_rejected(&discovery, &forged, "signature is invalid");
+    }
+
+    #[test]
+    fn rejects_wrong_issuer() {
+        let (_server, discovery) = issuer(&["test-key"]);
+        let mut claims = claims();
+        claims["iss"] = json!("https://evil.example.com");
+        assert_rejected(&discovery, &token(&claims), "was issued by");
+    }
+
+    #[test]
+    fn rejects_wrong_audience() {
+        let (_server, discovery) = issuer(&["test-key"]);
+        let mut claims = claims();
+        claims["aud"] = json!("another-client");
+        assert_rejected(&discovery, &token(&claims), "not intended for this client");
+    }
+
+    #[test]
+    fn multiple_audiences_need_azp() {
+        let (_server, discovery) = issuer(&["test-key"]);
+        let mut claims = claims();
+        claims["aud"] = json!([CLIENT_ID, "another-client"]);
+        assert_rejected(&discovery, &token(&claims), "multiple audiences");
+        claims["azp"] = json!(CLIENT_ID);
+        validate(&discovery, &token(&claims)).unwrap();
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        let (_server, discovery) = issuer(&["test-key"]);
+        let mut claims = claims();
+        claims["exp"] = json!(now() - CLOCK_SKEW_SECS - 60);
+        assert_rejected(&discovery, &token(&claims), "expired");
+    }
+
+    #[test]
+    fn rejects_wrong_nonce() {
+        let (_server, discovery) = issuer(&["test-key"]);
+        let mut claims = claims();
+        claims["nonce"] = json!("another-nonce");
+        assert_rejected(&discovery, &token(&claims), "nonce does not match");
+    }
+
+    #[test]
+    fn rejects_other_algorithms() {
+        let (_server, discovery) = issuer(&["test-key"]);
+        let id_token = sign(&json!({"alg": "HS256", "kid": "test-key"}), &claims());
+        assert_rejected(&discovery, &id_token, "unsupported id_token algorithm");
+    }
+
+    #[test]
+    fn unknown_key_refetches_keys() {
+        let (server, discovery) = issuer(&["test-key"]);
+        let jwks_fetches = || server.requests().iter().filter(|r| r.path == "/jwks").count();
+        let id_token = sign(&json!({"alg": "RS256", "kid": "rotated-key"}), &claims());
+        assert_rejected(&discovery, &id_token, "no signing key found");
+        assert_eq!(jwks_fetches(), 2);
+
+        // Once the issuer publishes the new key, it is picked up.
+        server.route("/jwks", &jwks(&["test-key", "rotated-key"]));
+        validate(&discovery, &id_token).unwrap();
+        assert_eq!(jwks_fetches(), 3);
+    }
+
+    #[test]
+    fn der_integers_are_minimal_and_positive() {
+        assert_eq!(der_integer(&[0x01, 0x00, 0x01]), vec![0x02, 0x03, 0x01, 0x00, 0x01]);
+        assert_eq!(der_integer(&[0x00, 0x00, 0x7f]), vec![0x02, 0x01, 0x7f]);
+        assert_eq!(der_integer(&[0x80, 0x01]), vec![0x02, 0x03, 0x00, 0x80, 0x01]);
+        assert_eq!(der_integer(&[0x00]), vec![0x02, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn der_long_lengths() {
+        let value = vec![0xab; 300];
+        let der = der_tagged(0x30, &value);
+        assert_eq!(&der[..4], &[0x30, 0x82, 0x01, 0x2c]);
+        assert_eq!(&der[4..], &value[..]);
+    }
+}
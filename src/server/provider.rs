@@ -0,0 +1,160 @@
+//! The `OAuthProvider` trait, and the registry of providers
+//! which can be named in the proxy configuration.
+//!
+//! A crate adding a provider implements `OAuthProvider`, and calls
+//! `register` before the proxy configuration is loaded:
+//!
+//! ```rust,ignore
+//! olaf2::server::register(MySso::new());
+//! ```
+//!
+//! The proxy configuration can then use `oauth_provider = "my-sso"`,
+//! where `"my-sso"` is the provider's `name`.
+
+use failure::{format_err, Error};
+use lazy_static::lazy_static;
+use oauth2::basic::BasicTokenResponse;
+use oauth2::{AccessToken, AuthType, AuthUrl, Scope, TokenUrl};
+use serde::de::{self, Deserialize, Deserializer};
+use serde_json::Value;
+use url::Url;
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
+
+use super::{Identity, Provider};
+
+/// An OAuth 2.0 provider, as used by the proxy.
+///
+/// Only the endpoints are required; the defaults suit a provider
+/// following RFC 6749 without extensions.
+pub trait OAuthProvider: Debug + Send + Sync {
+    /// Name used for the provider in the configuration.
+    fn name(&self) -> &str;
+
+    /// The authorization and token endpoints.
+    fn urls(&self) -> (AuthUrl, TokenUrl);
+
+    /// Scopes requested when none are configured.
+    fn default_scopes(&self) -> Vec<Scope> {
+        Vec::new()
+    }
+
+    /// Extra parameters for the authorization URL.
+    fn authorize_params(&self) -> Vec<(&'static str, String)> {
+        Vec::new()
+    }
+
+    /// How the proxy authenticates to the token endpoint.
+    fn auth_type(&self) -> AuthType {
+        AuthType::RequestBody
+    }
+
+    /// Parse a successful response from the token endpoint.
+    fn parse_token_response(&self, body: &Value) -> Result<BasicTokenResponse, Error> {
+        Ok(serde_json::from_value(body.clone())?)
+    }
+
+    /// Separator between the scopes granted in the token response.
+    fn scope_separator(&self) -> &str {
+        " "
+    }
+
+    /// Whether the provider supports PKCE (RFC 7636).
+    fn supports_pkce(&self) -> bool {
+        true
+    }
+
+    /// Endpoint for starting a device authorization grant (RFC 8628),
+    /// if the provider supports it.
+    fn device_auth_url(&self) -> Option<Url> {
+        None
+    }
+
+    /// The OpenID Connect issuer, if the provider's `id_token`s
+    /// should be requested and validated.
+    fn issuer(&self) -> Option<Url> {
+        None
+    }
+
+    /// Recover the `User`'s identity using their access `token`.
+    ///
+    /// Returns `None` if the provider cannot say who the `User` is.
+    fn identity(&self, _http: &reqwest::Client, _token: &AccessToken) -> Result<Option<Identity>, Error> {
+        Ok(None)
+    }
+}
+
+lazy_static! {
+    static ref REGISTRY: RwLock<HashMap<String, Arc<dyn OAuthProvider>>> = RwLock::new(HashMap::new());
+}
+
+/// Make `provider` available to the proxy under its `name`,
+/// replacing any provider previously registered with that name.
+pub fn register<P: 'static + OAuthProvider>(provider: P) {
+    let name = provider.name().to_string();
+    REGISTRY.write().unwrap().insert(name, Arc::new(provider));
+}
+
+/// The provider registered under `name`, if any.
+pub fn lookup(name: &str) -> Option<Arc<dyn OAuthProvider>> {
+    REGISTRY.read().unwrap().get(name).cloned()
+}
+
+/// The provider named in the configuration: either
+/// a built-in `Provider`, or one registered by name.
+#[derive(Clone, Debug)]
+pub enum ProviderConfig {
+    Builtin(Provider),
+    Registered(String),
+}
+
+/// Names of the built-in providers, as used in the configuration.
+const BUILTIN: &[&str] = &[
+    "Github", "GithubEnterprise", "GitLab", "Google", "Bitbucket", "AzureAd", "Custom", "Oidc",
+];
+
+impl<'de> Deserialize<'de> for ProviderConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Buffered, so that a built-in provider which fails to parse
+        // reports why, rather than that no variant matched.
+        let invalid = |e: serde_json::Error| -> D::Error {
+            de::Error::custom(format!("invalid oauth_provider: {}", e))
+        };
+        match Value::deserialize(deserializer)? {
+            Value::String(name) => match Provider::deserialize(Value::String(name.clone())) {
+                Ok(provider) => Ok(ProviderConfig::Builtin(provider)),
+                Err(e) => if BUILTIN.contains(&name.as_str()) {
+                    Err(invalid(e))
+                } else {
+                    Ok(ProviderConfig::Registered(name))
+                },
+            },
+            table @ Value::Object(_) => Provider::deserialize(table)
+                .map(ProviderConfig::Builtin)
+                .map_err(invalid),
+            other => Err(de::Error::custom(format!(
+                "invalid oauth_provider: expected a provider name or table, found {}", other
+            ))),
+        }
+    }
+}
+
+impl ProviderConfig {
+    /// Look up the configured provider.
+    pub fn resolve(&self) -> Result<Arc<dyn OAuthProvider>, Error> {
+        match self {
+            ProviderConfig::Builtin(provider) => Ok(Arc::new(provider.clone())),
+            ProviderConfig::Registered(name) => lookup(name).ok_or_else(|| {
+                format_err!("unknown provider `{}`; it must be registered before loading the configuration", name)
+            }),
+        }
+    }
+}
+
+impl From<Provider> for ProviderConfig {
+    fn from(provider: Provider) -> Self {
+        ProviderConfig::Builtin(provider)
+    }
+}